@@ -1,15 +1,18 @@
 use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::geometry::{Dimensions, Point};
 use embedded_graphics::mono_font::{ascii as fonts, MonoTextStyle};
-use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::pixelcolor::{Gray8, GrayColor as _};
 use embedded_graphics::text::{Baseline, Text};
 use embedded_graphics::Drawable as _;
-use rmox_common::eink_update::{EinkUpdateExt as _, UpdateStyle};
+use rmox_common::eink_update::{EinkUpdateAsyncExt as _, UpdateDepth, UpdateStyle};
 use rmox_common::types::Side;
-use rmox_fb::util::Scaled;
-use rmox_fb::Framebuffer;
+use rmox_fb::util::{Grayscale, Scaled};
+use rmox_fb::{Framebuffer, SurfaceBuffers};
 use rmox_protocol::client::recv::{Event, SurfaceEvent};
-use rmox_protocol::client::send::{Command, SurfaceInit};
+use rmox_protocol::client::send::{Command, Request, SurfaceInit};
+use rmox_protocol::handshake::Capability;
+use rmox_protocol::io::UnixTransport;
+use rmox_protocol::Serial;
 use tokio::{pin, select};
 use tokio_stream::StreamExt as _;
 
@@ -44,19 +47,32 @@ async fn main() {
 	let socket = tokio::net::UnixStream::connect(&socket_path)
 		.await
 		.unwrap_or_else(|error| panic!("connecting to {socket_path:?} (RMOX_SOCKET): {error}"));
-	let socket = rmox_protocol::io::Stream::new(socket);
+	let socket = UnixTransport::new(socket);
+	let (socket, _) = rmox_protocol::handshake::client(socket, Capability::FocusedSurfaceInfo.into())
+		.await
+		.unwrap_or_else(|error| panic!("handshake with WM: {error}"));
 	pin!(socket);
 
+	let mut serials = (0..).map(Serial);
+	let create_serial = serials.next().unwrap();
+
 	socket
-		.write(&Command::CreateSurface(SurfaceInit::Layer {
-			anchor: Side::Top,
-			size: 48,
-		}))
+		.write(&Request {
+			serial: create_serial,
+			command: Command::CreateSurface(SurfaceInit::Layer {
+				anchor: Side::Top,
+				size: 48,
+			}),
+		})
 		.await
 		.unwrap();
 
-	let mut fb = Framebuffer::open().unwrap();
-
+	let mut fb: Option<Framebuffer> = None;
+	let mut buffers_generation = 0;
+	// The serial of the commit that the WM has not acknowledged yet, and whether more was drawn since.
+	let mut pending_commit = None;
+	let mut needs_commit = false;
+	let mut surface = None;
 	let mut desc = None;
 
 	let mut time_interval = tokio::time::interval(std::time::Duration::from_secs(1));
@@ -64,19 +80,74 @@ async fn main() {
 
 	let mut time = time::OffsetDateTime::now_utc();
 	let mut battery = get_battery();
+	let mut title = None;
 
 	loop {
+		// The WM may still be reading the last committed buffer, which flipping after another commit would overwrite.
+		if needs_commit && pending_commit.is_none() {
+			if let (Some(surface_fb), Some(surface)) = (&mut fb, surface) {
+				// The updates were only recorded, so they tell the WM what to refresh.
+				let damage: Vec<_> = surface_fb
+					.take_update_log()
+					.unwrap()
+					.into_iter()
+					.map(|update| update.area)
+					.collect();
+				let serial = serials.next().unwrap();
+				socket
+					.write(&Request {
+						serial,
+						command: Command::Commit {
+							surface,
+							generation: buffers_generation,
+							damage: damage.clone(),
+							style: UpdateStyle::Monochrome,
+							depth: UpdateDepth::Partial,
+						},
+					})
+					.await
+					.unwrap();
+				surface_fb.swap_buffers(&damage);
+				pending_commit = Some(serial);
+				needs_commit = false;
+			}
+		}
+
 		select! {
 			res = socket.next() => {
 				let Some(res) = res else { break; };
 				let event = res.unwrap();
 				match dbg!(event) {
-					Event::Surface { id: _, event } => match event {
+					Event::Ack { serial, result } => {
+						if pending_commit == Some(serial) {
+							pending_commit = None;
+						}
+						if serial == create_serial {
+							result.expect("WM rejected surface creation");
+						} else if let Err(error) = result {
+							// The WM rejects other commands in normal cases, e.g., a `Commit` for a surface that it removed while the commit was in flight.
+							tracing::warn!(?serial, ?error, "WM rejected command");
+						}
+						continue;
+					}
+					Event::FocusedSurface(info) => {
+						title = info.and_then(|info| info.title);
+					}
+					Event::SelectionRequest { .. } | Event::SelectionCancelled { .. } => continue,
+					Event::Surface { id, event } => match event {
+						SurfaceEvent::Buffers { size, generation } => {
+							let fd = socket.take_fd().expect("WM did not send the surface buffers");
+							let buffers = SurfaceBuffers::open(fd, size).unwrap();
+							fb = Some(Framebuffer::from_surface_buffers(buffers));
+							buffers_generation = generation;
+							continue;
+						}
 						SurfaceEvent::Description(new_desc) => {
+							surface = Some(id);
 							desc = Some(new_desc);
 						}
 						SurfaceEvent::Quit => break,
-						SurfaceEvent::Input(..) => continue,
+						SurfaceEvent::Input(..) | SurfaceEvent::FocusIn | SurfaceEvent::FocusOut => continue,
 					}
 				}
 			}
@@ -86,19 +157,20 @@ async fn main() {
 			}
 		}
 
-		let Some(desc) = desc else {
+		let (Some(desc), Some(surface_fb)) = (desc, &mut fb) else {
 			continue;
 		};
 		if !desc.visible {
 			continue;
 		}
 
-		let mut fb = desc.transform(&mut fb);
+		let desc = desc.buffer_description();
+		let mut fb = Grayscale::new(desc.transform(surface_fb));
 		let bounds = fb.bounding_box();
-		fb.fill_solid(&bounds, Rgb565::new(0, 0, 0)).unwrap();
+		fb.fill_solid(&bounds, Gray8::BLACK).unwrap();
 		Text::with_baseline(
 			&format!(
-				"{:04}-{:02}-{:02} {:02}:{:02}:{:02} | {:>3.0}%{}",
+				"{:04}-{:02}-{:02} {:02}:{:02}:{:02} | {:>3.0}%{}{}{}",
 				time.year(),
 				time.month() as u8,
 				time.day(),
@@ -107,14 +179,19 @@ async fn main() {
 				time.second(),
 				battery.percentage,
 				if battery.charging { "^" } else { "v" },
+				if title.is_some() { " | " } else { "" },
+				title.as_deref().unwrap_or(""),
 			),
 			Point::new(bounds.top_left.x + 8, bounds.center().y) / 2,
-			MonoTextStyle::new(&fonts::FONT_7X14, Rgb565::new(31, 63, 31)),
+			MonoTextStyle::new(&fonts::FONT_7X14, Gray8::WHITE),
 			Baseline::Middle,
 		)
 		.draw(&mut Scaled::<_, 2>(&mut fb))
 		.unwrap();
-		fb.update_partial(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+		fb.update_partial_async(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+			.await
 			.unwrap();
+
+		needs_commit = true;
 	}
 }
@@ -3,15 +3,18 @@ use std::fmt::Write as _;
 use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::geometry::{Dimensions, Point};
 use embedded_graphics::mono_font::{ascii as fonts, MonoTextStyle};
-use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::pixelcolor::{Gray8, GrayColor as _};
 use embedded_graphics::text::{Baseline, Text};
 use embedded_graphics::Drawable as _;
-use rmox_common::eink_update::{EinkUpdateExt as _, UpdateStyle};
+use rmox_common::eink_update::{EinkUpdateAsyncExt as _, UpdateDepth, UpdateStyle};
 use rmox_common::types::Rectangle;
-use rmox_fb::util::Scaled;
-use rmox_fb::Framebuffer;
+use rmox_fb::util::{Grayscale, Scaled};
+use rmox_fb::{Framebuffer, SurfaceBuffers};
 use rmox_protocol::client::recv::{Event, SurfaceEvent};
-use rmox_protocol::client::send::{Command, SurfaceInit};
+use rmox_protocol::client::send::{Command, Request, SurfaceInit};
+use rmox_protocol::handshake::Capabilities;
+use rmox_protocol::io::UnixTransport;
+use rmox_protocol::Serial;
 use tokio::pin;
 use tokio_stream::StreamExt as _;
 
@@ -25,30 +28,99 @@ async fn main() {
 	let socket = tokio::net::UnixStream::connect(&socket_path)
 		.await
 		.unwrap_or_else(|error| panic!("connecting to {socket_path:?} (RMOX_SOCKET): {error}"));
-	let socket = rmox_protocol::io::Stream::new(socket);
+	let socket = UnixTransport::new(socket);
+	let (socket, _) = rmox_protocol::handshake::client(socket, Capabilities::all())
+		.await
+		.unwrap_or_else(|error| panic!("handshake with WM: {error}"));
 	pin!(socket);
 
+	let mut serials = (0..).map(Serial);
+	let create_serial = serials.next().unwrap();
+
 	socket
-		.write(&Command::CreateSurface(SurfaceInit::Normal))
+		.write(&Request {
+			serial: create_serial,
+			command: Command::CreateSurface(SurfaceInit::Normal),
+		})
 		.await
 		.unwrap();
 
-	let mut fb = Framebuffer::open().unwrap();
-
+	let mut fb: Option<Framebuffer> = None;
+	let mut buffers_generation = 0;
+	// The serial of the commit that the WM has not acknowledged yet, and whether more was drawn since.
+	let mut pending_commit = None;
+	let mut needs_commit = false;
+	let mut surface = None;
 	let mut desc = None;
 
 	let mut input_buf = "ready\n".to_owned();
 	let mut y = 8;
 
 	loop {
+		// The WM may still be reading the last committed buffer, which flipping after another commit would overwrite.
+		if needs_commit && pending_commit.is_none() {
+			if let (Some(surface_fb), Some(surface)) = (&mut fb, surface) {
+				// The updates were only recorded, so they tell the WM what to refresh.
+				let damage: Vec<_> = surface_fb
+					.take_update_log()
+					.unwrap()
+					.into_iter()
+					.map(|update| update.area)
+					.collect();
+				let serial = serials.next().unwrap();
+				socket
+					.write(&Request {
+						serial,
+						command: Command::Commit {
+							surface,
+							generation: buffers_generation,
+							damage: damage.clone(),
+							style: UpdateStyle::Monochrome,
+							depth: UpdateDepth::Partial,
+						},
+					})
+					.await
+					.unwrap();
+				surface_fb.swap_buffers(&damage);
+				pending_commit = Some(serial);
+				needs_commit = false;
+			}
+		}
+
 		let mut just_last_line = true;
 		let Some(res) = socket.next().await else {
 			break;
 		};
 		let event = res.unwrap();
 		match event {
-			Event::Surface { id: _, event } => match event {
+			Event::Ack { serial, result } => {
+				if pending_commit == Some(serial) {
+					pending_commit = None;
+				}
+				if serial == create_serial {
+					result.expect("WM rejected surface creation");
+				} else if let Err(error) = result {
+					// The WM rejects other commands in normal cases, e.g., a `Commit` for a surface that it removed while the commit was in flight.
+					tracing::warn!(?serial, ?error, "WM rejected command");
+				}
+				continue;
+			}
+			Event::FocusedSurface(info) => {
+				writeln!(input_buf, "focused surface: {info:?}").unwrap();
+			}
+			Event::SelectionRequest { .. } | Event::SelectionCancelled { .. } => continue,
+			Event::Surface { id, event } => match event {
+				SurfaceEvent::Buffers { size, generation } => {
+					let fd = socket
+						.take_fd()
+						.expect("WM did not send the surface buffers");
+					let buffers = SurfaceBuffers::open(fd, size).unwrap();
+					fb = Some(Framebuffer::from_surface_buffers(buffers));
+					buffers_generation = generation;
+					continue;
+				}
 				SurfaceEvent::Description(new_desc) => {
+					surface = Some(id);
 					desc = Some(new_desc);
 					just_last_line = false;
 				}
@@ -56,19 +128,23 @@ async fn main() {
 				SurfaceEvent::Input(input) => {
 					writeln!(input_buf, "{input:?}").unwrap();
 				}
+				focus @ (SurfaceEvent::FocusIn | SurfaceEvent::FocusOut) => {
+					writeln!(input_buf, "{focus:?}").unwrap();
+				}
 			},
 		}
 
-		let Some(desc) = desc else {
+		let (Some(desc), Some(surface_fb)) = (desc, &mut fb) else {
 			continue;
 		};
 		if !desc.visible {
 			continue;
 		}
 
-		let mut fb = desc.transform(&mut fb);
+		let desc = desc.buffer_description();
+		let mut fb = Grayscale::new(desc.transform(surface_fb));
 
-		let text_style = MonoTextStyle::new(&fonts::FONT_6X10, Rgb565::new(0, 0, 0));
+		let text_style = MonoTextStyle::new(&fonts::FONT_6X10, Gray8::BLACK);
 		if just_last_line {
 			let text = Text::with_baseline(
 				input_buf.lines().last().unwrap(),
@@ -80,14 +156,19 @@ async fn main() {
 			let mut bounds: Rectangle = text.bounding_box().into();
 			bounds = bounds.scale_all(2);
 			y = bounds.end().y;
-			fb.update_partial(&bounds, UpdateStyle::Monochrome).unwrap();
+			fb.update_partial_async(&bounds, UpdateStyle::Monochrome)
+				.await
+				.unwrap();
 		} else {
-			fb.clear(Rgb565::new(31, 63, 31)).unwrap();
+			fb.clear(Gray8::WHITE).unwrap();
 			let text = Text::with_baseline(&input_buf, Point::new(4, 4), text_style, Baseline::Top);
 			text.draw(&mut Scaled::<_, 2>(&mut fb)).unwrap();
 			y = Rectangle::from(text.bounding_box()).end().y * 2;
-			fb.update_partial(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+			fb.update_partial_async(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+				.await
 				.unwrap();
 		}
+
+		needs_commit = true;
 	}
 }
@@ -1,9 +1,15 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::task::{Poll, Waker};
+
 use embedded_graphics_core::geometry::Dimensions;
+use serde::{Deserialize, Serialize};
 
 use crate::types::Rectangle;
 
 /// How the E-Ink driver will refresh the pixels.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UpdateStyle {
 	/// A very fast method with minimal ghosting, but only works for black and white.
 	Monochrome,
@@ -11,10 +17,61 @@ pub enum UpdateStyle {
 	Rgb,
 	/// A slow method with no ghosting. Works for all colors.
 	Init,
+	/// A very fast method that only works for black and white, with more ghosting than [`Self::Monochrome`].
+	///
+	/// Suited to drawing ink as the pen moves.
+	/// The ghosting builds up, so the area should eventually be refreshed with another style.
+	A2,
+	/// A method that reduces flashing for black text on a white background.
+	/// Works for all colors.
+	Gl16,
+	/// A slower but higher-quality version of [`Self::Rgb`].
+	/// Works for all colors, and best suited to images.
+	Gc16,
+}
+
+/// The temperature that the E-Ink driver picks waveforms for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Temperature {
+	/// The fixed temperature used by the reMarkable's own drawing app.
+	#[default]
+	RemarkableDraw,
+	/// The temperature reported by the display's sensor.
+	Ambient,
+	/// A specific temperature in degrees Celsius.
+	Celsius(i32),
+}
+
+/// Dithering done by the E-Ink display controller as the pixels are refreshed.
+///
+/// This is unrelated to any dithering done while drawing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HardwareDither {
+	/// Pass the pixels through unchanged.
+	#[default]
+	Passthrough,
+	FloydSteinberg,
+	Atkinson,
+	Ordered,
+	/// Quantize to the levels of the waveform without dithering.
+	QuantizeOnly,
+}
+
+/// Lower-level parameters for an update.
+///
+/// The default matches what the reMarkable's own apps use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct UpdateOptions {
+	pub temperature: Temperature,
+	pub dither: HardwareDither,
+	/// Swap black and white.
+	pub invert: bool,
+	/// Quantize all pixels to black and white, regardless of the style.
+	pub force_monochrome: bool,
 }
 
 /// How much the E-Ink driver will try to remove ghosting.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UpdateDepth {
 	/// A normal and relatively fast update.
 	Partial,
@@ -22,8 +79,129 @@ pub enum UpdateDepth {
 	Full,
 }
 
+type WaitFn = Box<dyn FnOnce() -> std::io::Result<()> + Send>;
+
+/// Shared between [`UpdateHandle::wait`] and the waiter thread.
+#[derive(Default)]
+struct WaitShared {
+	result: Option<std::io::Result<()>>,
+	waker: Option<Waker>,
+}
+
+type WaitJob = (WaitFn, Arc<Mutex<WaitShared>>);
+
+/// The channel to a single thread that runs the waits of [`UpdateHandle::wait`] one after another,
+/// rather than spawning a thread for each update.
+///
+/// Waits complete in the order that updates were submitted, so running them in turn delays none of them much.
+fn waiter() -> &'static mpsc::Sender<WaitJob> {
+	static WAITER: OnceLock<mpsc::Sender<WaitJob>> = OnceLock::new();
+	WAITER.get_or_init(|| {
+		let (send, recv) = mpsc::channel::<WaitJob>();
+		std::thread::Builder::new()
+			.name("rmox-update-waiter".into())
+			.spawn(move || {
+				for (wait, shared) in recv {
+					let result = std::panic::catch_unwind(AssertUnwindSafe(wait))
+						.unwrap_or_else(|_| Err(std::io::Error::other("waiting for update panicked")));
+					let waker = {
+						let mut shared = shared.lock().unwrap();
+						shared.result = Some(result);
+						shared.waker.take()
+					};
+					if let Some(waker) = waker {
+						waker.wake();
+					}
+				}
+			})
+			.expect("spawn update waiter thread");
+		send
+	})
+}
+
+/// A submitted update, which can be waited on until the display has finished refreshing.
+pub struct UpdateHandle {
+	marker: u32,
+	wait: Option<WaitFn>,
+}
+
+impl std::fmt::Debug for UpdateHandle {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("UpdateHandle")
+			.field("marker", &self.marker)
+			.field("completed", &self.wait.is_none())
+			.finish()
+	}
+}
+
+impl UpdateHandle {
+	/// A handle for an update that is complete once `wait` returns.
+	///
+	/// `wait` may block, so [`Self::wait`] runs it on a shared waiter thread.
+	#[inline]
+	pub fn new(marker: u32, wait: impl FnOnce() -> std::io::Result<()> + Send + 'static) -> Self {
+		Self {
+			marker,
+			wait: Some(Box::new(wait)),
+		}
+	}
+
+	/// A handle for an update that has nothing to wait for, e.g., because its area was empty.
+	#[inline]
+	#[must_use]
+	pub fn completed(marker: u32) -> Self {
+		Self { marker, wait: None }
+	}
+
+	/// The marker that identifies this update to the E-Ink driver.
+	#[inline]
+	#[must_use]
+	pub fn marker(&self) -> u32 {
+		self.marker
+	}
+
+	/// Wait until the update has been fully displayed.
+	///
+	/// # Errors
+	///
+	/// Waiting failed or timed out.
+	pub async fn wait(self) -> std::io::Result<()> {
+		let Some(wait) = self.wait else {
+			return Ok(());
+		};
+		let shared = Arc::new(Mutex::new(WaitShared::default()));
+		waiter()
+			.send((wait, Arc::clone(&shared)))
+			.map_err(|_| std::io::Error::other("update waiter thread exited"))?;
+		std::future::poll_fn(|cx| {
+			let mut shared = shared.lock().unwrap();
+			if let Some(result) = shared.result.take() {
+				Poll::Ready(result)
+			} else {
+				shared.waker = Some(cx.waker().clone());
+				Poll::Pending
+			}
+		})
+		.await
+	}
+
+	/// Like [`Self::wait`], but blocks the current thread.
+	///
+	/// This should not be called from async code, since it stalls the executor.
+	///
+	/// # Errors
+	///
+	/// Same as [`Self::wait`].
+	#[inline]
+	pub fn wait_blocking(self) -> std::io::Result<()> {
+		self.wait.map_or(Ok(()), |wait| wait())
+	}
+}
+
 pub trait EinkUpdate {
-	/// Update `rect` using the specified `style` and `depth`.
+	/// Update `rect` using the specified `style`, `depth`, and `options`.
+	///
+	/// The returned handle can be used to wait for the update to complete.
 	///
 	/// The `style` determines how the E-Ink driver refreshes the pixels.
 	/// See the [`UpdateStyle`] docs for more info.
@@ -31,11 +209,19 @@ pub trait EinkUpdate {
 	/// The `depth` determines how hard the driver tries to remove ghosting.
 	/// See the [`UpdateDepth`] docs for more info.
 	///
+	/// The `options` are passed to the driver as-is.
+	/// See the [`UpdateOptions`] docs for more info.
+	///
 	/// # Errors
 	///
 	/// Writing to the rm2fb IPC channel.
-	fn update(&self, rect: &Rectangle, style: UpdateStyle, depth: UpdateDepth)
-		-> std::io::Result<()>;
+	fn update(
+		&self,
+		rect: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle>;
 }
 
 impl<T: EinkUpdate + ?Sized> EinkUpdate for &T {
@@ -45,8 +231,9 @@ impl<T: EinkUpdate + ?Sized> EinkUpdate for &T {
 		area: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
-		<T as EinkUpdate>::update(self, area, style, depth)
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		<T as EinkUpdate>::update(self, area, style, depth, options)
 	}
 }
 
@@ -57,44 +244,151 @@ impl<T: EinkUpdate + ?Sized> EinkUpdate for &mut T {
 		area: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
-		<T as EinkUpdate>::update(self, area, style, depth)
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		<T as EinkUpdate>::update(self, area, style, depth, options)
 	}
 }
 
 pub trait EinkUpdateExt: EinkUpdate {
-	/// [`EinkUpdate::update`] with [`UpdateDepth::Full`].
+	/// [`EinkUpdate::update`] with [`UpdateDepth::Full`] and the default [`UpdateOptions`].
 	///
 	/// # Errors
 	///
 	/// Same as [`EinkUpdate::update`].
 	#[inline]
-	fn update_full(&self, area: &Rectangle, style: UpdateStyle) -> std::io::Result<()> {
-		self.update(area, style, UpdateDepth::Full)
+	fn update_full(&self, area: &Rectangle, style: UpdateStyle) -> std::io::Result<UpdateHandle> {
+		self.update(area, style, UpdateDepth::Full, UpdateOptions::default())
 	}
 
-	/// [`EinkUpdate::update`] with [`UpdateDepth::Partial`].
+	/// [`EinkUpdate::update`] with [`UpdateDepth::Partial`] and the default [`UpdateOptions`].
 	///
 	/// # Errors
 	///
 	/// Same as [`EinkUpdate::update`].
 	#[inline]
-	fn update_partial(&self, area: &Rectangle, style: UpdateStyle) -> std::io::Result<()> {
-		self.update(area, style, UpdateDepth::Partial)
+	fn update_partial(&self, area: &Rectangle, style: UpdateStyle) -> std::io::Result<UpdateHandle> {
+		self.update(area, style, UpdateDepth::Partial, UpdateOptions::default())
 	}
 
-	/// [`EinkUpdate::update`] with the full bounding box of the framebuffer and [`UpdateDepth::Full`].
+	/// [`EinkUpdate::update`] with the full bounding box of the framebuffer, [`UpdateDepth::Full`], and the default [`UpdateOptions`].
 	///
 	/// # Errors
 	///
 	/// Same as [`EinkUpdate::update`].
 	#[inline]
-	fn update_all(&self, style: UpdateStyle) -> std::io::Result<()>
+	fn update_all(&self, style: UpdateStyle) -> std::io::Result<UpdateHandle>
 	where
 		Self: Dimensions,
 	{
-		self.update(&self.bounding_box().into(), style, UpdateDepth::Full)
+		self.update(
+			&self.bounding_box().into(),
+			style,
+			UpdateDepth::Full,
+			UpdateOptions::default(),
+		)
 	}
 }
 
 impl<T: EinkUpdate + ?Sized> EinkUpdateExt for T {}
+
+/// [`EinkUpdate`] for targets that can also submit updates without blocking.
+///
+/// This is separate from [`EinkUpdate`] so that it stays object-safe.
+pub trait EinkUpdateAsync: EinkUpdate {
+	/// Like [`EinkUpdate::update`], but never blocks the thread.
+	///
+	/// Where `update` would block because the driver's queue is full, this waits asynchronously instead.
+	/// This should be preferred in async code, since blocking would stall the executor.
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdate::update`],
+	/// except that errors writing to the rm2fb IPC channel may instead be reported by [`UpdateHandle::wait`].
+	fn update_async(
+		&self,
+		rect: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>>;
+}
+
+impl<T: EinkUpdateAsync + ?Sized> EinkUpdateAsync for &T {
+	#[inline]
+	fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>> {
+		<T as EinkUpdateAsync>::update_async(self, area, style, depth, options)
+	}
+}
+
+impl<T: EinkUpdateAsync + ?Sized> EinkUpdateAsync for &mut T {
+	#[inline]
+	fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>> {
+		<T as EinkUpdateAsync>::update_async(self, area, style, depth, options)
+	}
+}
+
+pub trait EinkUpdateAsyncExt: EinkUpdateAsync {
+	/// [`EinkUpdateAsync::update_async`] with [`UpdateDepth::Full`] and the default [`UpdateOptions`].
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdateAsync::update_async`].
+	#[inline]
+	fn update_full_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>> {
+		self.update_async(area, style, UpdateDepth::Full, UpdateOptions::default())
+	}
+
+	/// [`EinkUpdateAsync::update_async`] with [`UpdateDepth::Partial`] and the default [`UpdateOptions`].
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdateAsync::update_async`].
+	#[inline]
+	fn update_partial_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>> {
+		self.update_async(area, style, UpdateDepth::Partial, UpdateOptions::default())
+	}
+
+	/// [`EinkUpdateAsync::update_async`] with the full bounding box of the framebuffer, [`UpdateDepth::Full`], and the default [`UpdateOptions`].
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdateAsync::update_async`].
+	#[inline]
+	fn update_all_async(
+		&self,
+		style: UpdateStyle,
+	) -> impl Future<Output = std::io::Result<UpdateHandle>>
+	where
+		Self: Dimensions,
+	{
+		let area = self.bounding_box().into();
+		async move {
+			self
+				.update_async(&area, style, UpdateDepth::Full, UpdateOptions::default())
+				.await
+		}
+	}
+}
+
+impl<T: EinkUpdateAsync + ?Sized> EinkUpdateAsyncExt for T {}
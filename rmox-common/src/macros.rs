@@ -25,15 +25,15 @@ pub(crate) use enum_all;
 
 #[macro_export]
 macro_rules! mut_draw_target {
-	($ty:ty $(: [$($generics:tt)*])?) => {
-		impl$(<$($generics)*>)? $crate::__macro_private::embedded_graphics_core::geometry::OriginDimensions for &mut $ty {
+	($ty:ty $(: [$($generics:tt)*])? $(where [$($bounds:tt)*])?) => {
+		impl$(<$($generics)*>)? $crate::__macro_private::embedded_graphics_core::geometry::OriginDimensions for &mut $ty $(where $($bounds)*)? {
 			#[inline]
 			fn size(&self) -> $crate::__macro_private::embedded_graphics_core::geometry::Size {
 				<$ty as $crate::__macro_private::embedded_graphics_core::geometry::OriginDimensions>::size(*self)
 			}
 		}
 
-		impl$(<$($generics)*>)? $crate::__macro_private::embedded_graphics_core::draw_target::DrawTarget for &mut $ty {
+		impl$(<$($generics)*>)? $crate::__macro_private::embedded_graphics_core::draw_target::DrawTarget for &mut $ty $(where $($bounds)*)? {
 			type Color = <$ty as $crate::__macro_private::embedded_graphics_core::draw_target::DrawTarget>::Color;
 
 			type Error = <$ty as $crate::__macro_private::embedded_graphics_core::draw_target::DrawTarget>::Error;
@@ -177,6 +177,29 @@ impl Rectangle {
 		ret
 	}
 
+	/// The smallest rectangle that contains both rectangles.
+	#[inline]
+	#[must_use]
+	pub fn union(&self, other: &Self) -> Self {
+		let top_left = Pos2::min_components(self.top_left(), other.top_left());
+		let bottom_right = Pos2::max_components(self.bottom_right(), other.bottom_right());
+		Self::from_corners(top_left, bottom_right)
+	}
+
+	/// Whether the rectangles overlap or share part of an edge.
+	/// Rectangles that only share a corner do not touch.
+	#[inline]
+	#[must_use]
+	pub fn touches(&self, other: &Self) -> bool {
+		let (a_start, a_end) = (self.top_left(), self.bottom_right());
+		let (b_start, b_end) = (other.top_left(), other.bottom_right());
+		let overlap_x = a_start.x < b_end.x && b_start.x < a_end.x;
+		let overlap_y = a_start.y < b_end.y && b_start.y < a_end.y;
+		let touch_x = a_start.x <= b_end.x && b_start.x <= a_end.x;
+		let touch_y = a_start.y <= b_end.y && b_start.y <= a_end.y;
+		(overlap_x && touch_y) || (touch_x && overlap_y)
+	}
+
 	#[inline]
 	#[must_use]
 	pub fn from_corners(origin: Pos2, end: Pos2) -> Self {
@@ -347,6 +370,20 @@ fn test_points() {
 	assert_eq!(points, [pos2(2, 2), pos2(3, 2), pos2(2, 3), pos2(3, 3)]);
 }
 
+#[test]
+fn test_touches() {
+	let a = rect(0, 0, 10, 10);
+	assert!(a.touches(&rect(5, 5, 10, 10)));
+	// Sharing an edge.
+	assert!(a.touches(&rect(10, 2, 5, 5)));
+	assert!(a.touches(&rect(0, -3, 1, 3)));
+	// Sharing only a corner.
+	assert!(!a.touches(&rect(10, 10, 5, 5)));
+	assert!(!a.touches(&rect(11, 0, 5, 5)));
+
+	assert_eq!(a.union(&rect(10, 2, 5, 10)), rect(0, 0, 15, 12));
+}
+
 impl From<embedded_graphics_core::primitives::Rectangle> for Rectangle {
 	#[inline]
 	#[must_use]
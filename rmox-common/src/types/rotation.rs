@@ -55,9 +55,9 @@ impl Rotation {
 	#[must_use]
 	pub fn inverse(self) -> Self {
 		match self {
-			Self::None => Self::Rotate180,
+			Self::None => Self::None,
 			Self::Rotate90 => Self::Rotate270,
-			Self::Rotate180 => Self::None,
+			Self::Rotate180 => Self::Rotate180,
 			Self::Rotate270 => Self::Rotate90,
 		}
 	}
@@ -88,6 +88,25 @@ fn test_transform_point() {
 	);
 }
 
+#[test]
+fn test_inverse() {
+	use crate::types::{pos2, vec2};
+
+	let container = vec2(3, 5);
+	let origin = pos2(1, 2);
+	for &rotation in Rotation::ALL {
+		let transformed = rotation.transform_point(origin, container);
+		let inverse_container = rotation.transform_size(container).abs();
+		assert_eq!(
+			rotation
+				.inverse()
+				.transform_point(transformed, inverse_container),
+			origin,
+			"{rotation:?}",
+		);
+	}
+}
+
 #[test]
 fn test_transform_rect() {
 	use crate::types::{rect, vec2};
@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::os::fd::{FromRawFd as _, OwnedFd};
+
+use embedded_graphics_core::pixelcolor::Rgb565;
+use memmap2::MmapMut;
+use rmox_common::types::{Pos2, Rectangle, Vec2};
+
+/// Two buffers of pixels in shared memory, so that a surface can draw into one while the WM composites the other.
+///
+/// The buffers are stored one after the other, each row-major with no padding.
+/// Both sides start with buffer 0 as the back buffer, and [flip](Self::flip) after each commit.
+/// The WM may read the committed buffer until it acks the commit, so a surface must wait for the ack before committing again.
+#[derive(Debug)]
+pub struct SurfaceBuffers {
+	mapping: MmapMut,
+	size: Vec2,
+	back: usize,
+}
+
+impl SurfaceBuffers {
+	fn len_bytes(size: Vec2) -> u64 {
+		// Two buffers.
+		2 * u64::try_from(size.x * size.y).unwrap()
+			* u64::try_from(size_of::<Rgb565>()).unwrap_or_else(|_| unreachable!())
+	}
+
+	/// Create buffers of `size` in new anonymous shared memory.
+	///
+	/// The returned descriptor can be sent to a client to open the buffers with [`Self::open`].
+	/// The memory is sealed so that the client cannot resize it from under us.
+	///
+	/// # Errors
+	///
+	/// Creating, resizing, sealing, or mapping the memory.
+	///
+	/// # Panics
+	///
+	/// If the size is not positive.
+	pub fn create(size: Vec2) -> std::io::Result<(Self, OwnedFd)> {
+		tracing::debug!(?size, "create surface buffers");
+		assert!(
+			size.x > 0 && size.y > 0,
+			"surface buffers must not be empty"
+		);
+
+		// SAFETY: The name is a valid C string.
+		let fd = unsafe {
+			libc::memfd_create(
+				c"rmox-surface".as_ptr(),
+				libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+			)
+		};
+		if fd < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		// SAFETY: We own the new descriptor.
+		let file = unsafe { File::from_raw_fd(fd) };
+		file.set_len(Self::len_bytes(size))?;
+		let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+		// SAFETY: Plain `fcntl` on a descriptor that we own.
+		if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		let buffers = Self::map(&file, size)?;
+		Ok((buffers, file.into()))
+	}
+
+	/// Open buffers of `size` that were created by [`Self::create`].
+	///
+	/// # Errors
+	///
+	/// - The memory being too small for the size
+	/// - Mapping the memory
+	pub fn open(fd: OwnedFd, size: Vec2) -> std::io::Result<Self> {
+		tracing::debug!(?size, "open surface buffers");
+
+		let file = File::from(fd);
+		if file.metadata()?.len() < Self::len_bytes(size) {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"surface buffers are too small",
+			));
+		}
+		Self::map(&file, size)
+	}
+
+	fn map(file: &File, size: Vec2) -> std::io::Result<Self> {
+		let len = usize::try_from(Self::len_bytes(size)).unwrap();
+		// SAFETY: The memory is shared with the other side, which may write to it at any time.
+		// That only causes tearing, since the pixels are plain integers,
+		// and the memory cannot shrink because `create` seals it.
+		let mapping = unsafe { memmap2::MmapOptions::new().len(len).map_mut(file) }?;
+		Ok(Self {
+			mapping,
+			size,
+			back: 0,
+		})
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn size(&self) -> Vec2 {
+		self.size
+	}
+
+	fn buffer(&self, index: usize) -> &[u16] {
+		let pixels: &[u16] = bytemuck::cast_slice(&self.mapping);
+		let (first, second) = pixels.split_at(pixels.len() / 2);
+		if index == 0 {
+			first
+		} else {
+			second
+		}
+	}
+
+	fn buffer_mut(&mut self, index: usize) -> &mut [u16] {
+		let pixels: &mut [u16] = bytemuck::cast_slice_mut(&mut self.mapping);
+		let (first, second) = pixels.split_at_mut(pixels.len() / 2);
+		if index == 0 {
+			first
+		} else {
+			second
+		}
+	}
+
+	/// The buffer that is drawn into for the next commit.
+	#[inline]
+	#[must_use]
+	pub fn back(&self) -> &[u16] {
+		self.buffer(self.back)
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn back_mut(&mut self) -> &mut [u16] {
+		self.buffer_mut(self.back)
+	}
+
+	/// The buffer that was last committed.
+	#[inline]
+	#[must_use]
+	pub fn front(&self) -> &[u16] {
+		self.buffer(1 - self.back)
+	}
+
+	/// Swap the front and back buffers.
+	#[inline]
+	pub fn flip(&mut self) {
+		self.back = 1 - self.back;
+	}
+
+	/// [Flip](Self::flip) the buffers, then copy `damage` from the new front buffer into the back buffer,
+	/// so that drawing can continue from what was committed.
+	///
+	/// The buffers only differ where the committed buffer was drawn into,
+	/// so `damage` must cover everything that was drawn since the last flip.
+	pub fn flip_and_copy(&mut self, damage: &[Rectangle]) {
+		self.flip();
+		let bounds = Rectangle::new(Pos2::ZERO, self.size);
+		// The damage is clipped to the buffers, so none of the coordinates are negative.
+		let to_usize = |n: i32| usize::try_from(n).unwrap_or_else(|_| unreachable!());
+		let width = to_usize(self.size.x);
+		let back = self.back;
+		let pixels: &mut [u16] = bytemuck::cast_slice_mut(&mut self.mapping);
+		let (first, second) = pixels.split_at_mut(pixels.len() / 2);
+		let (dest, src) = if back == 0 {
+			(first, &*second)
+		} else {
+			(second, &*first)
+		};
+		for rect in damage {
+			let rect = rect.intersection(&bounds);
+			if rect.is_empty() {
+				continue;
+			}
+			let x = to_usize(rect.origin.x);
+			let row_width = to_usize(rect.size.x);
+			for y in rect.origin.y..rect.end().y {
+				let start = to_usize(y) * width + x;
+				dest[start..][..row_width].copy_from_slice(&src[start..][..row_width]);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_surface_buffers() {
+	use rmox_common::types::{rect, vec2};
+
+	let size = vec2(3, 2);
+	let (mut server, fd) = SurfaceBuffers::create(size).unwrap();
+	let mut client = SurfaceBuffers::open(fd, size).unwrap();
+	assert_eq!(client.back().len(), 6);
+
+	client.back_mut()[1] = 0xffff;
+	client.flip_and_copy(&[rect(1, 0, 1, 1)]);
+	assert_eq!(server.back()[1], 0xffff);
+	server.flip();
+
+	client.back_mut()[2] = 0xffff;
+	assert_eq!(server.front(), [0, 0xffff, 0, 0, 0, 0]);
+	assert_eq!(server.back(), [0, 0xffff, 0xffff, 0, 0, 0]);
+}
+
+#[test]
+fn test_flip_and_copy_damage() {
+	use rmox_common::types::{rect, vec2};
+
+	let (mut buffers, _fd) = SurfaceBuffers::create(vec2(3, 3)).unwrap();
+	buffers.back_mut().fill(1);
+	buffers.flip_and_copy(&[Rectangle::new(Pos2::ZERO, vec2(3, 3))]);
+	assert_eq!(buffers.back(), [1; 9]);
+
+	// Only the damage is copied, clipped to the buffers.
+	buffers.back_mut().fill(2);
+	buffers.flip_and_copy(&[rect(1, 1, 5, 1), rect(0, 0, 1, 1)]);
+	assert_eq!(buffers.front(), [2; 9]);
+	assert_eq!(buffers.back(), [2, 1, 1, 1, 2, 2, 1, 1, 1]);
+}
@@ -1,69 +1,82 @@
-use rmox_common::eink_update::{UpdateDepth, UpdateStyle};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use rmox_common::eink_update::{
+	HardwareDither, Temperature, UpdateDepth, UpdateHandle, UpdateOptions, UpdateStyle,
+};
 use rmox_common::types::Rectangle;
+use tokio::sync::mpsc;
 
+use self::semaphore::Semaphore;
 use self::xsi_queue::XsiQueue;
-use crate::Framebuffer;
 
+mod semaphore;
 mod xsi_queue;
 
-#[derive(Debug)]
-pub struct Channel {
-	queue: XsiQueue,
-}
+// Message types, from the `MSG_TYPE` enum in rm2fb.
+const UPDATE_MESSAGE_TYPE: i32 = 2;
+const WAIT_MESSAGE_TYPE: i32 = 4;
 
-impl Channel {
-	const QUEUE_KEY: libc::key_t = 0x2257c;
+/// Generous, since a full `Init` refresh takes around a second.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
 
-	pub fn open() -> std::io::Result<Self> {
-		tracing::debug!("open channel");
+/// How many updates can be queued for the sender thread before `update_async` waits for room.
+const SENDER_CAPACITY: usize = 16;
 
-		Ok(Self {
-			queue: XsiQueue::open(Self::QUEUE_KEY)?,
-		})
-	}
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
+#[repr(C)]
+struct RawUpdate {
+	top: u32,
+	left: u32,
+	width: u32,
+	height: u32,
+	waveform_mode: u32,
+	update_mode: u32,
+	update_marker: u32,
+	temp: i32,
+	flags: u32,
+	dither_mode: i32,
+	quant_bit: i32,
+	_unused: [u32; 7],
+}
 
-	pub fn _update(
-		&self,
+impl RawUpdate {
+	fn new(
 		rect: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
-		#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
-		#[repr(C)]
-		struct Raw {
-			top: u32,
-			left: u32,
-			width: u32,
-			height: u32,
-			waveform_mode: u32,
-			update_mode: u32,
-			update_marker: u32,
-			temp: i32,
-			flags: u32,
-			dither_mode: i32,
-			quant_bit: i32,
-			_unused: [u32; 7],
+		options: UpdateOptions,
+		marker: u32,
+	) -> Self {
+		let mut flags = 0;
+		if options.invert {
+			// `EPDC_FLAG_ENABLE_INVERSION`.
+			flags |= 0x01;
 		}
-
-		tracing::debug!(?rect, ?style, ?depth, "channel update");
-
-		let rect = rect.intersection(&Framebuffer::RECT);
-		if rect.is_empty() {
-			return Ok(());
+		if options.force_monochrome {
+			// `EPDC_FLAG_FORCE_MONOCHROME`.
+			flags |= 0x02;
 		}
 
-		let raw = Raw {
+		Self {
 			top: rect.origin.y.try_into().unwrap(),
 			left: rect.origin.x.try_into().unwrap(),
 			width: rect.size.x.try_into().unwrap(),
 			height: rect.size.y.try_into().unwrap(),
+			// The waveform modes of the rM1's `mxcfb` driver, which rm2fb translates for the rM2's display.
 			waveform_mode: match style {
 				// Init.
 				UpdateStyle::Init => 0x0,
-				// Gc16-fast.
-				UpdateStyle::Rgb => 0x3,
 				// Direct update.
 				UpdateStyle::Monochrome => 0x1,
+				// Gc16.
+				UpdateStyle::Gc16 => 0x2,
+				// Gc16-fast.
+				UpdateStyle::Rgb => 0x3,
+				// A2.
+				UpdateStyle::A2 => 0x4,
+				// Gl16.
+				UpdateStyle::Gl16 => 0x5,
 			},
 			update_mode: match depth {
 				// Full update.
@@ -71,18 +84,156 @@ impl Channel {
 				// Partial update.
 				UpdateDepth::Partial => 0,
 			},
-			// Unused since we don't wait for updates (yet).
-			update_marker: 1,
-			// "Remarkable draw" mode.
-			temp: 0x0018,
-			flags: 0,
-			// Tell EPDC to use dithering passthrough.
-			dither_mode: 0,
+			update_marker: marker,
+			temp: match options.temperature {
+				// "Remarkable draw" mode.
+				Temperature::RemarkableDraw => 0x0018,
+				// `TEMP_USE_AMBIENT`.
+				Temperature::Ambient => 0x1000,
+				Temperature::Celsius(celsius) => celsius,
+			},
+			flags,
+			// The `EPDC_FLAG_USE_DITHERING_*` modes.
+			dither_mode: match options.dither {
+				HardwareDither::Passthrough => 0,
+				HardwareDither::FloydSteinberg => 1,
+				HardwareDither::Atkinson => 2,
+				HardwareDither::Ordered => 3,
+				HardwareDither::QuantizeOnly => 4,
+			},
 			// No idea what this does.
 			quant_bit: 0,
 			_unused: [0; 7],
-		};
-		// Update message type.
-		self.queue.send(2, bytemuck::bytes_of(&raw))
+		}
+	}
+}
+
+/// An update for the sender thread, along with a channel to report the result of sending it.
+///
+/// The result is received by [`UpdateHandle::wait_blocking`], which may run inside a runtime, so this is not a Tokio channel.
+type QueuedUpdate = (RawUpdate, std::sync::mpsc::SyncSender<std::io::Result<()>>);
+
+#[derive(Debug)]
+pub struct Channel {
+	queue: XsiQueue,
+	/// Spawned on the first call to `update_async`.
+	sender: OnceLock<mpsc::Sender<QueuedUpdate>>,
+}
+
+impl Channel {
+	pub fn open(key: libc::key_t) -> std::io::Result<Self> {
+		tracing::debug!(key, "open channel");
+
+		Ok(Self {
+			queue: XsiQueue::open(key)?,
+			sender: OnceLock::new(),
+		})
+	}
+
+	/// `rect` must already be clipped to the framebuffer.
+	///
+	/// Blocks if the rm2fb queue is full.
+	pub fn update(
+		&self,
+		rect: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+		marker: u32,
+	) -> std::io::Result<UpdateHandle> {
+		tracing::debug!(?rect, ?style, ?depth, ?options, marker, "channel update");
+
+		if rect.is_empty() {
+			return Ok(UpdateHandle::completed(marker));
+		}
+
+		let raw = RawUpdate::new(rect, style, depth, options, marker);
+		self
+			.queue
+			.send(UPDATE_MESSAGE_TYPE, bytemuck::bytes_of(&raw))?;
+
+		let queue = self.queue;
+		Ok(UpdateHandle::new(marker, move || wait(queue, marker)))
 	}
+
+	/// Like `update`, but the message is sent by a dedicated thread so that a full rm2fb queue does not block the caller.
+	/// Instead, if the sender thread falls behind, this waits asynchronously for room.
+	///
+	/// Errors from sending the message are reported when waiting on the returned handle.
+	pub async fn update_async(
+		&self,
+		rect: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+		marker: u32,
+	) -> std::io::Result<UpdateHandle> {
+		tracing::debug!(
+			?rect,
+			?style,
+			?depth,
+			?options,
+			marker,
+			"channel async update"
+		);
+
+		if rect.is_empty() {
+			return Ok(UpdateHandle::completed(marker));
+		}
+
+		let raw = RawUpdate::new(rect, style, depth, options, marker);
+		let (sent_send, sent_recv) = std::sync::mpsc::sync_channel(1);
+		self
+			.sender()
+			.send((raw, sent_send))
+			.await
+			.map_err(|_| std::io::Error::other("update sender thread exited"))?;
+
+		let queue = self.queue;
+		Ok(UpdateHandle::new(marker, move || {
+			// The wait message must not overtake the update.
+			sent_recv
+				.recv()
+				.map_err(|_| std::io::Error::other("update sender thread exited"))??;
+			wait(queue, marker)
+		}))
+	}
+
+	fn sender(&self) -> &mpsc::Sender<QueuedUpdate> {
+		self.sender.get_or_init(|| {
+			let (send, mut recv) = mpsc::channel::<QueuedUpdate>(SENDER_CAPACITY);
+			let queue = self.queue;
+			// The thread exits once `self` is dropped, closing the channel.
+			std::thread::Builder::new()
+				.name("rmox-fb-sender".into())
+				.spawn(move || {
+					while let Some((raw, sent)) = recv.blocking_recv() {
+						let res = queue.send(UPDATE_MESSAGE_TYPE, bytemuck::bytes_of(&raw));
+						if let Err(error) = &res {
+							tracing::warn!(?error, marker = raw.update_marker, "sending queued update");
+						}
+						// The handle may have been dropped without waiting.
+						_ = sent.send(res);
+					}
+				})
+				.expect("spawn update sender thread");
+			send
+		})
+	}
+}
+
+/// Block until rm2fb reports that the EPDC has completed its updates.
+///
+/// rm2fb does not wait for specific markers, so this waits for every update that was submitted before the wait message,
+/// including the one with `marker`.
+fn wait(queue: XsiQueue, marker: u32) -> std::io::Result<()> {
+	tracing::debug!(marker, "wait for update");
+
+	// rm2fb posts to the semaphore with the name in the message once the updates are complete.
+	let name = format!("/rmox.wait.{}.{marker}", std::process::id());
+	let semaphore = Semaphore::create(&name)?;
+	let mut raw = [0u8; 512];
+	raw[..name.len()].copy_from_slice(name.as_bytes());
+	queue.send(WAIT_MESSAGE_TYPE, &raw)?;
+	semaphore.wait_timeout(WAIT_TIMEOUT)
 }
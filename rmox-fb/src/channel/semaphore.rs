@@ -0,0 +1,65 @@
+use std::ffi::CString;
+use std::time::{Duration, SystemTime};
+
+/// A safe wrapper for a named POSIX semaphore, which is unlinked when dropped.
+#[derive(Debug)]
+pub struct Semaphore {
+	handle: *mut libc::sem_t,
+	name: CString,
+}
+
+impl Semaphore {
+	/// Create a semaphore with the given `name` and an initial value of 0.
+	///
+	/// Fails if a semaphore with the name already exists.
+	pub fn create(name: &str) -> std::io::Result<Self> {
+		let name = CString::new(name)?;
+		// SAFETY: The name is a valid C string, and the variadic arguments are the mode and value as required by `O_CREAT`.
+		let handle = unsafe {
+			libc::sem_open(
+				name.as_ptr(),
+				libc::O_CREAT | libc::O_EXCL,
+				0o644 as libc::c_uint,
+				0 as libc::c_uint,
+			)
+		};
+		if handle == libc::SEM_FAILED {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(Self { handle, name })
+	}
+
+	/// Wait until the semaphore can be decremented, or until `timeout` elapses.
+	pub fn wait_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+		let deadline = (SystemTime::now() + timeout)
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap();
+		// `time_t` and `c_long` are 32 bits on the rM2.
+		#[allow(clippy::unnecessary_fallible_conversions)]
+		let deadline = libc::timespec {
+			tv_sec: deadline.as_secs().try_into().unwrap(),
+			tv_nsec: deadline.subsec_nanos().try_into().unwrap(),
+		};
+
+		loop {
+			// SAFETY: The handle is open for the lifetime of `self`.
+			if unsafe { libc::sem_timedwait(self.handle, std::ptr::addr_of!(deadline)) } == 0 {
+				return Ok(());
+			}
+			let error = std::io::Error::last_os_error();
+			if error.kind() != std::io::ErrorKind::Interrupted {
+				return Err(error);
+			}
+		}
+	}
+}
+
+impl Drop for Semaphore {
+	fn drop(&mut self) {
+		// SAFETY: The handle was opened in `create` and is not used after this.
+		unsafe {
+			libc::sem_close(self.handle);
+			libc::sem_unlink(self.name.as_ptr());
+		}
+	}
+}
@@ -1,7 +1,9 @@
 /// A safe wrapper for an XSI message queue.
 ///
 /// Currently only supports sending because that is what we need here.
-#[derive(Debug)]
+///
+/// Message queues do not need to be closed, so this can be freely copied.
+#[derive(Debug, Clone, Copy)]
 pub struct XsiQueue {
 	handle: libc::c_int,
 }
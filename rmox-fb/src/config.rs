@@ -0,0 +1,111 @@
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use rmox_common::types::Vec2;
+
+use crate::Framebuffer;
+
+/// Where to find an rm2fb-compatible framebuffer, and its size.
+///
+/// The default matches rm2fb on the rM2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FramebufferConfig {
+	/// The shared memory file containing the pixels.
+	pub shm_path: PathBuf,
+	/// The key of the XSI message queue that receives update requests.
+	pub queue_key: i32,
+	/// The size of the framebuffer in pixels.
+	pub size: Vec2,
+}
+
+impl Default for FramebufferConfig {
+	#[inline]
+	fn default() -> Self {
+		Self {
+			shm_path: "/dev/shm/swtfb.01".into(),
+			queue_key: 0x2257c,
+			size: Framebuffer::SIZE,
+		}
+	}
+}
+
+impl FramebufferConfig {
+	pub const SHM_PATH_VAR: &'static str = "RMOX_FB_SHM_PATH";
+	pub const QUEUE_KEY_VAR: &'static str = "RMOX_FB_QUEUE_KEY";
+	pub const WIDTH_VAR: &'static str = "RMOX_FB_WIDTH";
+	pub const HEIGHT_VAR: &'static str = "RMOX_FB_HEIGHT";
+
+	/// The default config, with each field overridden by its environment variable if set.
+	///
+	/// The queue key may be written in decimal or in hexadecimal with a `0x` prefix.
+	///
+	/// # Errors
+	///
+	/// An environment variable is set but invalid.
+	pub fn from_env() -> std::io::Result<Self> {
+		let mut ret = Self::default();
+		if let Some(path) = std::env::var_os(Self::SHM_PATH_VAR) {
+			ret.shm_path = path.into();
+		}
+		if let Some(key) = std::env::var_os(Self::QUEUE_KEY_VAR) {
+			ret.queue_key = parse_var(Self::QUEUE_KEY_VAR, &key, |key| {
+				match key.strip_prefix("0x") {
+					Some(hex) => i32::from_str_radix(hex, 16).ok(),
+					None => key.parse().ok(),
+				}
+			})?;
+		}
+		if let Some(width) = std::env::var_os(Self::WIDTH_VAR) {
+			ret.size.x = parse_var(Self::WIDTH_VAR, &width, parse_dimension)?;
+		}
+		if let Some(height) = std::env::var_os(Self::HEIGHT_VAR) {
+			ret.size.y = parse_var(Self::HEIGHT_VAR, &height, parse_dimension)?;
+		}
+		Ok(ret)
+	}
+}
+
+fn parse_dimension(raw: &str) -> Option<i32> {
+	raw.parse().ok().filter(|&v| v > 0)
+}
+
+fn parse_var<T>(
+	name: &str,
+	value: &OsStr,
+	parse: impl FnOnce(&str) -> Option<T>,
+) -> std::io::Result<T> {
+	value.to_str().and_then(parse).ok_or_else(|| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("invalid value for {name}: {:?}", value.to_string_lossy()),
+		)
+	})
+}
+
+#[test]
+fn test_from_env() {
+	// Environment variables are process-wide, so this is the only test that sets them.
+	std::env::set_var(FramebufferConfig::QUEUE_KEY_VAR, "0x1234");
+	std::env::set_var(FramebufferConfig::WIDTH_VAR, "100");
+	let config = FramebufferConfig::from_env().unwrap();
+	assert_eq!(config.queue_key, 0x1234);
+	assert_eq!(
+		config.size,
+		Vec2 {
+			x: 100,
+			y: Framebuffer::HEIGHT
+		}
+	);
+	assert_eq!(config.shm_path, FramebufferConfig::default().shm_path);
+
+	std::env::set_var(FramebufferConfig::HEIGHT_VAR, "-1");
+	assert!(FramebufferConfig::from_env().is_err());
+
+	for var in [
+		FramebufferConfig::QUEUE_KEY_VAR,
+		FramebufferConfig::WIDTH_VAR,
+		FramebufferConfig::HEIGHT_VAR,
+	] {
+		std::env::remove_var(var);
+	}
+}
@@ -16,29 +16,60 @@
 #![warn(clippy::pedantic)]
 // Unsafe code is allowed in this crate due to the low-level interfacing.
 
+use std::sync::atomic::{AtomicU32, Ordering};
+
 use embedded_graphics_core::draw_target::DrawTarget;
 use embedded_graphics_core::geometry::{Dimensions, OriginDimensions, Size};
 use embedded_graphics_core::pixelcolor::raw::{RawData, RawU16};
-use embedded_graphics_core::pixelcolor::Rgb565;
+use embedded_graphics_core::pixelcolor::{Rgb565, RgbColor as _};
 use embedded_graphics_core::primitives::{PointsIter as _, Rectangle as BadRect};
-use rmox_common::eink_update::{EinkUpdate, UpdateDepth, UpdateStyle};
+use rmox_common::eink_update::{
+	EinkUpdate, EinkUpdateAsync, UpdateDepth, UpdateHandle, UpdateOptions, UpdateStyle,
+};
 use rmox_common::mut_draw_target;
-use rmox_common::types::{Pos2, Rectangle, Vec2};
+use rmox_common::types::{pos2, Pos2, Rectangle, Vec2};
 
+pub use crate::buffers::SurfaceBuffers;
 use crate::channel::Channel;
+pub use crate::config::FramebufferConfig;
 use crate::mapping::Mapping;
+pub use crate::memory::UpdateRecord;
+use crate::memory::{Memory, UpdateLog};
+pub use crate::screenshot::Screenshot;
 
+mod buffers;
 mod channel;
+mod config;
 mod mapping;
+mod memory;
+mod screenshot;
 pub mod util;
 
+/// Update markers are unique within the process, since they are also used to name the semaphores for waiting.
+static NEXT_MARKER: AtomicU32 = AtomicU32::new(1);
+
 #[derive(Debug)]
 pub struct Framebuffer {
-	mapping: Mapping,
-	channel: Channel,
+	backend: Backend,
+	size: Vec2,
+}
+
+#[derive(Debug)]
+enum Backend {
+	/// The shared memory and message queue of rm2fb.
+	Rm2fb { mapping: Mapping, channel: Channel },
+	/// Used for headless operation, e.g., in tests.
+	Memory(Memory),
+	/// The back buffer of a surface, which the WM composites into the real framebuffer.
+	Surface {
+		buffers: SurfaceBuffers,
+		log: UpdateLog,
+	},
 }
 
 impl Framebuffer {
+	// The dimensions of the rM2's display, which are the default.
+	// Use the methods of the same names to get the dimensions of a particular framebuffer.
 	pub const WIDTH: i32 = rmox_common::fb::WIDTH;
 	pub const HEIGHT: i32 = rmox_common::fb::HEIGHT;
 	pub const SIZE: Vec2 = Vec2 {
@@ -50,25 +81,203 @@ impl Framebuffer {
 		size: Self::SIZE,
 	};
 
+	/// Open the framebuffer using [`FramebufferConfig::from_env`].
+	///
+	/// This draws to and updates the display directly, which is only meant for the WM.
+	/// WM clients should draw into their [surface buffers](Self::from_surface_buffers) and let the WM refresh the display when they commit.
+	///
+	/// # Errors
+	///
+	/// - Reading the config from the environment
+	/// - Same as [`Self::open_with`]
+	#[deprecated(
+		note = "WM clients should use `Framebuffer::from_surface_buffers`, and the WM `Framebuffer::open_with`"
+	)]
+	#[inline]
+	pub fn open() -> std::io::Result<Self> {
+		Self::open_with(&FramebufferConfig::from_env()?)
+	}
+
+	/// Open the framebuffer described by `config`.
+	///
+	/// This draws to and updates the display directly, which is only meant for the WM.
+	///
 	/// # Errors
 	///
 	/// - Opening the framebuffer
+	/// - The framebuffer's size not matching `config.size`
 	/// - Mapping the framebuffer
 	/// - Getting the rm2fb IPC channel
 	#[inline]
-	pub fn open() -> std::io::Result<Self> {
-		tracing::debug!("open framebuffer");
+	pub fn open_with(config: &FramebufferConfig) -> std::io::Result<Self> {
+		tracing::debug!(?config, "open framebuffer");
 
 		Ok(Self {
-			mapping: Mapping::open()?,
-			channel: Channel::open()?,
+			backend: Backend::Rm2fb {
+				mapping: Mapping::open(&config.shm_path, config.size)?,
+				channel: Channel::open(config.queue_key)?,
+			},
+			size: config.size,
 		})
 	}
 
+	/// Create a framebuffer of the default size that is backed by memory rather than rm2fb.
+	///
+	/// Updates are not displayed anywhere; they are recorded and can be retrieved with [`Self::take_update_log`].
+	#[inline]
+	#[must_use]
+	pub fn headless() -> Self {
+		Self {
+			backend: Backend::Memory(Memory::new(Self::SIZE)),
+			size: Self::SIZE,
+		}
+	}
+
+	/// Create a framebuffer that draws into the back buffer of a surface.
+	///
+	/// Like a [headless](Self::headless) framebuffer, updates are recorded and can be retrieved with [`Self::take_update_log`],
+	/// which is useful for finding the damage to commit.
+	/// The surface's buffers are shown by committing them to the WM, followed by [`Self::swap_buffers`].
+	/// Only one commit may be in flight at a time, as explained for [`SurfaceBuffers`].
+	#[inline]
+	#[must_use]
+	pub fn from_surface_buffers(buffers: SurfaceBuffers) -> Self {
+		Self {
+			size: buffers.size(),
+			backend: Backend::Surface {
+				buffers,
+				log: UpdateLog::default(),
+			},
+		}
+	}
+
+	/// Swap the surface buffers after committing the back buffer with `damage`, as in [`SurfaceBuffers::flip_and_copy`].
+	///
+	/// Does nothing if the framebuffer was not created with [`Self::from_surface_buffers`].
+	#[inline]
+	pub fn swap_buffers(&mut self, damage: &[Rectangle]) {
+		if let Backend::Surface { buffers, .. } = &mut self.backend {
+			buffers.flip_and_copy(damage);
+		}
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn width(&self) -> i32 {
+		self.size.x
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn height(&self) -> i32 {
+		self.size.y
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn rect(&self) -> Rectangle {
+		Rectangle::new(Pos2::ZERO, self.size)
+	}
+
+	/// Take the updates that have been requested since the last call.
+	///
+	/// Returns `None` if the framebuffer is backed by rm2fb, since its updates are sent to the display.
+	#[inline]
+	#[must_use]
+	pub fn take_update_log(&self) -> Option<Vec<UpdateRecord>> {
+		match &self.backend {
+			Backend::Rm2fb { .. } => None,
+			Backend::Memory(Memory { log, .. }) | Backend::Surface { log, .. } => Some(log.take()),
+		}
+	}
+
+	#[inline]
+	#[must_use]
+	pub fn pixels(&self) -> &[u16] {
+		match &self.backend {
+			Backend::Rm2fb { mapping, .. } => mapping.pixels(),
+			Backend::Memory(memory) => memory.pixels(),
+			Backend::Surface { buffers, .. } => buffers.back(),
+		}
+	}
+
 	#[inline]
 	#[must_use]
 	pub fn pixels_mut(&mut self) -> &mut [u16] {
-		self.mapping.pixels_mut()
+		match &mut self.backend {
+			Backend::Rm2fb { mapping, .. } => mapping.pixels_mut(),
+			Backend::Memory(memory) => memory.pixels_mut(),
+			Backend::Surface { buffers, .. } => buffers.back_mut(),
+		}
+	}
+
+	/// Copy the part within `clip` of row-major `pixels` the size of `area` into `area`, clipped to the framebuffer.
+	///
+	/// # Panics
+	///
+	/// If the number of pixels does not match the size of `area`.
+	pub fn copy_from(&mut self, area: &Rectangle, pixels: &[u16], clip: &Rectangle) {
+		assert_eq!(
+			usize::try_from(area.size.x * area.size.y).ok(),
+			Some(pixels.len()),
+			"pixels do not match area",
+		);
+		let clipped = area.intersection(clip).intersection(&self.rect());
+		if clipped.is_empty() {
+			return;
+		}
+
+		let src_width = usize::try_from(area.size.x).unwrap();
+		let src_x = usize::try_from(clipped.origin.x - area.origin.x).unwrap();
+		let width = usize::try_from(clipped.size.x).unwrap();
+		for y in clipped.origin.y..clipped.origin.y + clipped.size.y {
+			let src_y = usize::try_from(y - area.origin.y).unwrap();
+			let src = &pixels[src_y * src_width + src_x..][..width];
+			let index = self.point_to_index(pos2(clipped.origin.x, y));
+			self.pixels_mut()[index..][..width].copy_from_slice(src);
+		}
+	}
+
+	/// Read back the pixels in `area`, clipped to the framebuffer.
+	#[must_use]
+	pub fn read(&self, area: &Rectangle) -> Screenshot {
+		let area = area.intersection(&self.rect());
+		self.read_mapped(area.size, |point| point + area.origin.to_vec())
+	}
+
+	/// Read back an image of `size`, where each pixel is taken from the framebuffer at `map(point)`.
+	///
+	/// This allows reading back an area in another orientation, e.g., as a surface sees it.
+	/// Pixels that map outside of the framebuffer are black.
+	#[must_use]
+	pub fn read_mapped(&self, size: Vec2, map: impl Fn(Pos2) -> Pos2) -> Screenshot {
+		let rect = self.rect();
+		let pixels = self.pixels();
+		let pixels = Rectangle::new(Pos2::ZERO, size)
+			.points()
+			.map(|point| {
+				let point = map(point);
+				if rect.contains(point) {
+					RawU16::new(pixels[self.point_to_index(point)]).into()
+				} else {
+					Rgb565::BLACK
+				}
+			})
+			.collect();
+		Screenshot::new(size, pixels)
+	}
+
+	/// Does not bounds-check the point.
+	#[must_use]
+	fn point_to_index(&self, point: Pos2) -> usize {
+		usize::try_from(point.y).unwrap() * usize::try_from(self.size.x).unwrap()
+			+ usize::try_from(point.x).unwrap()
+	}
+
+	/// Does not bounds-check the point.
+	fn set_pixel(&mut self, point: Pos2, color: Rgb565) {
+		let index = self.point_to_index(point);
+		self.pixels_mut()[index] = RawU16::from(color).into_inner();
 	}
 }
 
@@ -76,7 +285,7 @@ impl OriginDimensions for Framebuffer {
 	#[inline]
 	#[must_use]
 	fn size(&self) -> Size {
-		Self::SIZE.try_into().unwrap()
+		self.size.try_into().unwrap()
 	}
 }
 
@@ -91,7 +300,7 @@ impl DrawTarget for Framebuffer {
 		let bounds = self.bounding_box();
 		let pixels = pixels.into_iter().filter(|pixel| bounds.contains(pixel.0));
 		for pixel in pixels {
-			self.mapping.set_pixel(pixel.0.into(), pixel.1);
+			self.set_pixel(pixel.0.into(), pixel.1);
 		}
 		Ok(())
 	}
@@ -111,12 +320,12 @@ impl DrawTarget for Framebuffer {
 		// Only filter if part of `area` is out-of-bounds.
 		if &intersection == area {
 			for pixel in pixels {
-				self.mapping.set_pixel(pixel.0.into(), pixel.1);
+				self.set_pixel(pixel.0.into(), pixel.1);
 			}
 		} else {
 			let pixels = pixels.into_iter().filter(|pixel| bounds.contains(pixel.0));
 			for pixel in pixels {
-				self.mapping.set_pixel(pixel.0.into(), pixel.1);
+				self.set_pixel(pixel.0.into(), pixel.1);
 			}
 		}
 
@@ -126,9 +335,10 @@ impl DrawTarget for Framebuffer {
 	fn fill_solid(&mut self, area: &BadRect, color: Self::Color) -> Result<(), Self::Error> {
 		let area = area.intersection(&self.bounding_box());
 		let color = RawU16::from(color).into_inner();
-		let pixels = self.mapping.pixels_mut();
+		let width = usize::try_from(self.size.x).unwrap();
+		let pixels = self.pixels_mut();
 		for y in area.rows() {
-			let y_index = Mapping::point_to_index(Pos2 { x: 0, y });
+			let y_index = usize::try_from(y).unwrap() * width;
 			let x_range = area.columns();
 			let x_range = usize::try_from(x_range.start).unwrap()..usize::try_from(x_range.end).unwrap();
 			pixels[y_index..][x_range].fill(color);
@@ -137,10 +347,7 @@ impl DrawTarget for Framebuffer {
 	}
 
 	fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
-		self
-			.mapping
-			.pixels_mut()
-			.fill(RawU16::from(color).into_inner());
+		self.pixels_mut().fill(RawU16::from(color).into_inner());
 		Ok(())
 	}
 }
@@ -152,9 +359,79 @@ impl EinkUpdate for Framebuffer {
 		area: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
-		self.channel._update(area, style, depth)
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		let marker = NEXT_MARKER.fetch_add(1, Ordering::Relaxed);
+		let area = area.intersection(&self.rect());
+		match &self.backend {
+			Backend::Rm2fb { channel, .. } => channel.update(&area, style, depth, options, marker),
+			Backend::Memory(Memory { log, .. }) | Backend::Surface { log, .. } => {
+				log.record(&area, style, depth, options);
+				Ok(UpdateHandle::completed(marker))
+			}
+		}
+	}
+}
+
+impl EinkUpdateAsync for Framebuffer {
+	async fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		let marker = NEXT_MARKER.fetch_add(1, Ordering::Relaxed);
+		let area = area.intersection(&self.rect());
+		match &self.backend {
+			Backend::Rm2fb { channel, .. } => {
+				channel
+					.update_async(&area, style, depth, options, marker)
+					.await
+			}
+			Backend::Memory(Memory { log, .. }) | Backend::Surface { log, .. } => {
+				log.record(&area, style, depth, options);
+				Ok(UpdateHandle::completed(marker))
+			}
+		}
 	}
 }
 
 mut_draw_target!(Framebuffer);
+
+#[test]
+fn test_headless() {
+	use embedded_graphics_core::pixelcolor::RgbColor as _;
+	use rmox_common::eink_update::EinkUpdateExt as _;
+	use rmox_common::types::{pos2, vec2};
+
+	let mut fb = Framebuffer::headless();
+	let area = Rectangle::new(pos2(10, 20), vec2(3, 4));
+	fb.fill_solid(&area.into(), Rgb565::WHITE).unwrap();
+	assert_eq!(fb.pixels()[fb.point_to_index(pos2(12, 23))], 0xffff);
+	assert_eq!(fb.pixels()[fb.point_to_index(pos2(13, 23))], 0);
+
+	fb.update_partial(&area, UpdateStyle::Monochrome).unwrap();
+	// Entirely out of bounds, so not recorded.
+	fb.update_partial(&area.with_x(-10), UpdateStyle::Monochrome)
+		.unwrap();
+	fb.update_all(UpdateStyle::Init).unwrap();
+	assert_eq!(
+		fb.take_update_log().unwrap(),
+		[
+			UpdateRecord {
+				area,
+				style: UpdateStyle::Monochrome,
+				depth: UpdateDepth::Partial,
+				options: UpdateOptions::default(),
+			},
+			UpdateRecord {
+				area: Framebuffer::RECT,
+				style: UpdateStyle::Init,
+				depth: UpdateDepth::Full,
+				options: UpdateOptions::default(),
+			},
+		],
+	);
+	assert_eq!(fb.take_update_log().unwrap(), []);
+}
@@ -1,9 +1,8 @@
-use embedded_graphics_core::pixelcolor::raw::{RawData, RawU16};
+use std::path::Path;
+
 use embedded_graphics_core::pixelcolor::Rgb565;
 use memmap2::MmapMut;
-use rmox_common::types::Pos2;
-
-use crate::Framebuffer;
+use rmox_common::types::Vec2;
 
 #[derive(Debug)]
 pub struct Mapping {
@@ -11,41 +10,40 @@ pub struct Mapping {
 }
 
 impl Mapping {
-	const PATH: &'static str = "/dev/shm/swtfb.01";
-
-	/// Does not bounds-check the point.
-	#[must_use]
-	pub fn point_to_index(point: Pos2) -> usize {
-		usize::try_from(point.y).unwrap() * usize::try_from(Framebuffer::WIDTH).unwrap()
-			+ usize::try_from(point.x).unwrap()
-	}
-
-	pub fn open() -> std::io::Result<Self> {
-		tracing::debug!("open framebuffer mapping");
+	/// The shared memory is owned by the rm2fb shim and mapped by other clients too,
+	/// so it is never resized; a size other than that of the framebuffer is an error instead.
+	pub fn open(path: &Path, size: Vec2) -> std::io::Result<Self> {
+		tracing::debug!(?path, ?size, "open framebuffer mapping");
 
-		let size_bytes = u64::try_from(Framebuffer::WIDTH * Framebuffer::HEIGHT)
-			.unwrap_or_else(|_| unreachable!())
+		let size_bytes = u64::try_from(size.x * size.y).unwrap_or_else(|_| unreachable!())
 			* u64::try_from(std::mem::size_of::<Rgb565>()).unwrap_or_else(|_| unreachable!());
 
 		let file = std::fs::OpenOptions::new()
 			.read(true)
 			.write(true)
-			.open(Self::PATH)?;
-		file.set_len(size_bytes)?;
+			.open(path)?;
+		let len = file.metadata()?.len();
+		if len != size_bytes {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("framebuffer shm is {len} bytes, but {size_bytes} bytes are needed for a size of {size:?}"),
+			));
+		}
 		// SAFETY: Yeah, the buffer is shared and can change underneath us.
-		// But in practice we are using it as a write-only bitbucket so it's not really an issue.
+		// But in practice we mostly use it as a write-only bitbucket so it's not really an issue.
+		// It is only read back for screenshots, which can tolerate some tearing.
 		// And it _probably_ won't change while we're accessing it.
 		let mapping = unsafe { MmapMut::map_mut(&file) }?;
 		Ok(Self { mapping })
 	}
 
 	#[must_use]
-	pub fn pixels_mut(&mut self) -> &mut [u16] {
-		bytemuck::cast_slice_mut(&mut self.mapping)
+	pub fn pixels(&self) -> &[u16] {
+		bytemuck::cast_slice(&self.mapping)
 	}
 
-	/// Does not bounds-check the point.
-	pub fn set_pixel(&mut self, point: Pos2, color: Rgb565) {
-		self.pixels_mut()[Self::point_to_index(point)] = RawU16::from(color).into_inner();
+	#[must_use]
+	pub fn pixels_mut(&mut self) -> &mut [u16] {
+		bytemuck::cast_slice_mut(&mut self.mapping)
 	}
 }
@@ -0,0 +1,76 @@
+use std::sync::Mutex;
+
+use rmox_common::eink_update::{UpdateDepth, UpdateOptions, UpdateStyle};
+use rmox_common::types::{Rectangle, Vec2};
+
+/// An update that was requested from a headless framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateRecord {
+	/// Clipped to the framebuffer, like the updates sent to rm2fb.
+	pub area: Rectangle,
+	pub style: UpdateStyle,
+	pub depth: UpdateDepth,
+	pub options: UpdateOptions,
+}
+
+/// Updates that were recorded instead of being sent to the display.
+#[derive(Debug, Default)]
+pub struct UpdateLog(Mutex<Vec<UpdateRecord>>);
+
+/// Pixels in a plain buffer, with updates recorded into a log instead of being sent to the display.
+#[derive(Debug)]
+pub struct Memory {
+	pixels: Vec<u16>,
+	pub log: UpdateLog,
+}
+
+impl Memory {
+	pub fn new(size: Vec2) -> Self {
+		tracing::debug!(?size, "create in-memory framebuffer");
+
+		let len = usize::try_from(size.x * size.y).unwrap();
+		Self {
+			pixels: vec![0; len],
+			log: UpdateLog::default(),
+		}
+	}
+
+	#[must_use]
+	pub fn pixels(&self) -> &[u16] {
+		&self.pixels
+	}
+
+	#[must_use]
+	pub fn pixels_mut(&mut self) -> &mut [u16] {
+		&mut self.pixels
+	}
+}
+
+impl UpdateLog {
+	/// `area` must already be clipped to the framebuffer.
+	pub fn record(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) {
+		tracing::debug!(?area, ?style, ?depth, ?options, "record update");
+
+		// Mirror `Channel::update`, which skips empty updates.
+		if area.is_empty() {
+			return;
+		}
+
+		self.0.lock().unwrap().push(UpdateRecord {
+			area: *area,
+			style,
+			depth,
+			options,
+		});
+	}
+
+	pub fn take(&self) -> Vec<UpdateRecord> {
+		std::mem::take(&mut self.0.lock().unwrap())
+	}
+}
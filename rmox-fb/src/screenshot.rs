@@ -0,0 +1,94 @@
+use embedded_graphics_core::pixelcolor::{Rgb565, Rgb888, RgbColor as _};
+use rmox_common::types::Vec2;
+
+/// Pixels read back from a [`Framebuffer`](crate::Framebuffer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+	size: Vec2,
+	/// Row-major.
+	pixels: Vec<Rgb565>,
+}
+
+impl Screenshot {
+	/// # Panics
+	///
+	/// If the size is negative or the number of pixels does not match it.
+	#[must_use]
+	pub fn new(size: Vec2, pixels: Vec<Rgb565>) -> Self {
+		assert!(size.x >= 0 && size.y >= 0, "negative screenshot size");
+		assert_eq!(
+			usize::try_from(size.x * size.y).ok(),
+			Some(pixels.len()),
+			"screenshot size does not match pixels",
+		);
+		Self { size, pixels }
+	}
+
+	#[must_use]
+	pub fn size(&self) -> Vec2 {
+		self.size
+	}
+
+	#[must_use]
+	pub fn pixels(&self) -> &[Rgb565] {
+		&self.pixels
+	}
+
+	/// Encode the screenshot as an 8-bit RGB PNG.
+	///
+	/// # Errors
+	///
+	/// The screenshot is empty, since PNG does not allow images without pixels.
+	pub fn encode_png(&self) -> std::io::Result<Vec<u8>> {
+		// Checked in `new`.
+		let width = u32::try_from(self.size.x).unwrap_or_else(|_| unreachable!());
+		let height = u32::try_from(self.size.y).unwrap_or_else(|_| unreachable!());
+
+		let mut data = Vec::with_capacity(self.pixels.len() * 3);
+		for &pixel in &self.pixels {
+			let pixel = Rgb888::from(pixel);
+			data.extend([pixel.r(), pixel.g(), pixel.b()]);
+		}
+
+		let mut png = Vec::new();
+		let mut encoder = png::Encoder::new(&mut png, width, height);
+		encoder.set_color(png::ColorType::Rgb);
+		encoder.set_depth(png::BitDepth::Eight);
+		let mut writer = encoder.write_header()?;
+		writer.write_image_data(&data)?;
+		writer.finish()?;
+		Ok(png)
+	}
+}
+
+#[test]
+fn test_screenshot() {
+	use embedded_graphics_core::draw_target::DrawTarget as _;
+	use rmox_common::types::{pos2, rect, vec2};
+
+	use crate::Framebuffer;
+
+	let mut fb = Framebuffer::headless();
+	fb.fill_solid(&rect(10, 10, 2, 1).into(), Rgb565::WHITE)
+		.unwrap();
+
+	let screenshot = fb.read(&rect(9, 10, 4, 2));
+	assert_eq!(screenshot.size(), vec2(4, 2));
+	let (b, w) = (Rgb565::BLACK, Rgb565::WHITE);
+	assert_eq!(screenshot.pixels(), [b, w, w, b, b, b, b, b]);
+	// Clipped to the framebuffer.
+	assert_eq!(fb.read(&rect(-1, -1, 2, 2)).size(), vec2(1, 1));
+
+	// Transposed, so the white pixels are in a column.
+	let screenshot = fb.read_mapped(vec2(2, 3), |point| pos2(point.y + 9, point.x + 10));
+	assert_eq!(screenshot.pixels(), [b, b, w, b, w, b]);
+
+	let png = screenshot.encode_png().unwrap();
+	let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+	let mut data = vec![0; reader.output_buffer_size()];
+	reader.next_frame(&mut data).unwrap();
+	assert_eq!((reader.info().width, reader.info().height), (2, 3));
+	assert_eq!(&data[6..12], [255, 255, 255, 0, 0, 0]);
+
+	assert!(fb.read(&rect(-1, -1, 1, 1)).encode_png().is_err());
+}
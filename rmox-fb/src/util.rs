@@ -1,8 +1,15 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
 use embedded_graphics_core::draw_target::DrawTarget;
-use embedded_graphics_core::geometry::{OriginDimensions, Size};
+use embedded_graphics_core::geometry::{OriginDimensions, Point, Size};
+use embedded_graphics_core::pixelcolor::{Gray8, GrayColor, PixelColor};
 use embedded_graphics_core::primitives::Rectangle as BadRect;
 use embedded_graphics_core::Pixel;
-use rmox_common::eink_update::{EinkUpdate, UpdateDepth, UpdateStyle};
+use rmox_common::eink_update::{
+	EinkUpdate, EinkUpdateAsync, UpdateDepth, UpdateHandle, UpdateOptions, UpdateStyle,
+};
 use rmox_common::mut_draw_target;
 use rmox_common::types::Rectangle;
 
@@ -48,8 +55,568 @@ impl<T: EinkUpdate, const N: usize> EinkUpdate for Scaled<T, N> {
 		area: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
 		let area = area.scale_all(N.try_into().unwrap());
-		self.0.update(&area, style, depth)
+		self.0.update(&area, style, depth, options)
+	}
+}
+
+impl<T: EinkUpdateAsync, const N: usize> EinkUpdateAsync for Scaled<T, N> {
+	async fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		let area = area.scale_all(N.try_into().unwrap());
+		self.0.update_async(&area, style, depth, options).await
+	}
+}
+
+/// Collects the updates requested through it and submits them on [`Self::commit`],
+/// merging those with the same style, depth, and options that overlap or share an edge.
+///
+/// Each update has a fixed cost, so this is useful when many small areas are redrawn at once.
+/// Drawing is passed through to the inner target.
+pub struct Coalescing<T> {
+	inner: T,
+	pending: RefCell<Vec<(PendingKey, Vec<Rectangle>)>>,
+}
+
+/// Updates are only merged if all of these are the same.
+type PendingKey = (UpdateStyle, UpdateDepth, UpdateOptions);
+
+impl<T> Coalescing<T> {
+	#[inline]
+	pub fn new(inner: T) -> Self {
+		Self {
+			inner,
+			pending: RefCell::new(Vec::new()),
+		}
+	}
+
+	/// Any pending updates are discarded.
+	#[inline]
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+
+	fn push(&self, area: &Rectangle, key: PendingKey) {
+		if area.is_empty() {
+			return;
+		}
+		let mut pending = self.pending.borrow_mut();
+		if let Some((_, areas)) = pending.iter_mut().find(|(other, _)| *other == key) {
+			areas.push(*area);
+		} else {
+			pending.push((key, vec![*area]));
+		}
+	}
+
+	/// Take the pending updates, merged into as few rectangles as possible.
+	fn take_coalesced(&self) -> Vec<(PendingKey, Vec<Rectangle>)> {
+		let mut pending = self.pending.take();
+		for (_, areas) in &mut pending {
+			coalesce(areas);
+		}
+		pending
+	}
+}
+
+impl<T: EinkUpdate> Coalescing<T> {
+	/// Submit the pending updates to the inner target.
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdate::update`].
+	/// The remaining updates are discarded.
+	pub fn commit(&self) -> std::io::Result<Vec<UpdateHandle>> {
+		let mut handles = Vec::new();
+		for ((style, depth, options), areas) in self.take_coalesced() {
+			for area in areas {
+				handles.push(self.inner.update(&area, style, depth, options)?);
+			}
+		}
+		Ok(handles)
+	}
+}
+
+impl<T: EinkUpdateAsync> Coalescing<T> {
+	/// Like [`Self::commit`], but using [`EinkUpdateAsync::update_async`].
+	///
+	/// # Errors
+	///
+	/// Same as [`EinkUpdateAsync::update_async`].
+	/// The remaining updates are discarded.
+	pub async fn commit_async(&self) -> std::io::Result<Vec<UpdateHandle>> {
+		let mut handles = Vec::new();
+		for ((style, depth, options), areas) in self.take_coalesced() {
+			for area in areas {
+				handles.push(
+					self
+						.inner
+						.update_async(&area, style, depth, options)
+						.await?,
+				);
+			}
+		}
+		Ok(handles)
+	}
+}
+
+/// Merge rectangles that touch until none do.
+fn coalesce(areas: &mut Vec<Rectangle>) {
+	let mut i = 0;
+	while i < areas.len() {
+		let mut merged = false;
+		let mut j = i + 1;
+		while j < areas.len() {
+			if areas[i].touches(&areas[j]) {
+				let other = areas.swap_remove(j);
+				areas[i] = areas[i].union(&other);
+				merged = true;
+			} else {
+				j += 1;
+			}
+		}
+		// The grown rectangle may now touch one that was already checked.
+		i = if merged { 0 } else { i + 1 };
+	}
+}
+
+impl<T: OriginDimensions> OriginDimensions for Coalescing<T> {
+	fn size(&self) -> Size {
+		self.inner.size()
+	}
+}
+
+impl<T: DrawTarget + OriginDimensions> DrawTarget for Coalescing<T> {
+	type Color = T::Color;
+
+	type Error = T::Error;
+
+	fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Pixel<Self::Color>>,
+	{
+		self.inner.draw_iter(pixels)
+	}
+
+	fn fill_contiguous<I>(&mut self, area: &BadRect, colors: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Self::Color>,
+	{
+		self.inner.fill_contiguous(area, colors)
+	}
+
+	fn fill_solid(&mut self, area: &BadRect, color: Self::Color) -> Result<(), Self::Error> {
+		self.inner.fill_solid(area, color)
+	}
+
+	fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+		self.inner.clear(color)
+	}
+}
+
+mut_draw_target!(Coalescing<T>: [T: DrawTarget + OriginDimensions]);
+
+/// The updates are recorded until [`Coalescing::commit`] is called,
+/// so the returned handles are already complete and have a marker of 0.
+impl<T> EinkUpdate for Coalescing<T> {
+	fn update(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		self.push(area, (style, depth, options));
+		Ok(UpdateHandle::completed(0))
+	}
+}
+
+impl<T> EinkUpdateAsync for Coalescing<T> {
+	async fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		self.update(area, style, depth, options)
+	}
+}
+
+/// Draws grayscale colors, such as [`Gray8`] and [`Gray4`](embedded_graphics_core::pixelcolor::Gray4),
+/// to a target with another color type, such as a [`Framebuffer`](crate::Framebuffer) or a `Transformed` one.
+///
+/// The display is grayscale, so this avoids needing to pick RGB colors for each shade.
+pub struct Grayscale<T, C = Gray8> {
+	inner: T,
+	_color: PhantomData<C>,
+}
+
+impl<T, C> Grayscale<T, C> {
+	#[inline]
+	pub fn new(inner: T) -> Self {
+		Self {
+			inner,
+			_color: PhantomData,
+		}
+	}
+
+	#[inline]
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+}
+
+impl<T: OriginDimensions, C> OriginDimensions for Grayscale<T, C> {
+	fn size(&self) -> Size {
+		self.inner.size()
+	}
+}
+
+impl<T: DrawTarget + OriginDimensions, C: GrayColor + Into<T::Color>> DrawTarget
+	for Grayscale<T, C>
+{
+	type Color = C;
+
+	type Error = T::Error;
+
+	fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Pixel<Self::Color>>,
+	{
+		self.inner.draw_iter(
+			pixels
+				.into_iter()
+				.map(|Pixel(pos, color)| Pixel(pos, color.into())),
+		)
+	}
+
+	fn fill_contiguous<I>(&mut self, area: &BadRect, colors: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Self::Color>,
+	{
+		self
+			.inner
+			.fill_contiguous(area, colors.into_iter().map(Into::into))
+	}
+
+	fn fill_solid(&mut self, area: &BadRect, color: Self::Color) -> Result<(), Self::Error> {
+		self.inner.fill_solid(area, color.into())
+	}
+
+	fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+		self.inner.clear(color.into())
+	}
+}
+
+mut_draw_target!(Grayscale<T, C>: [T: DrawTarget + OriginDimensions, C: GrayColor + Into<T::Color>]);
+
+impl<T: EinkUpdate, C> EinkUpdate for Grayscale<T, C> {
+	fn update(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		self.inner.update(area, style, depth, options)
+	}
+}
+
+impl<T: EinkUpdateAsync, C> EinkUpdateAsync for Grayscale<T, C> {
+	fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> impl std::future::Future<Output = std::io::Result<UpdateHandle>> {
+		self.inner.update_async(area, style, depth, options)
+	}
+}
+
+/// How [`Dithered`] quantizes colors to black and white.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DitherMode {
+	/// Ordered dithering with a 4x4 Bayer matrix.
+	///
+	/// Each pixel is quantized independently, so this is cheap and stable when redrawing part of an area.
+	Bayer,
+	/// Error diffusion.
+	///
+	/// Looks better for images, but the error is only diffused within a single draw call,
+	/// and only exactly when the pixels are drawn in row-major order.
+	FloydSteinberg,
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+#[allow(clippy::cast_sign_loss)] // Masked to `0..4`.
+fn bayer_threshold(point: Point) -> i16 {
+	let level = BAYER_4X4[(point.y & 3) as usize][(point.x & 3) as usize];
+	i16::from(level) * 16 + 8
+}
+
+/// Returns whether the value is quantized to white, as well as the quantization error.
+fn quantize(value: i16, threshold: i16) -> (bool, i16) {
+	if value >= threshold {
+		(true, value - 255)
+	} else {
+		(false, value)
+	}
+}
+
+fn luma(color: impl Into<Gray8>) -> i16 {
+	color.into().luma().into()
+}
+
+/// Quantizes colors to pure black and white before drawing them to the inner target,
+/// so that [`UpdateStyle::Monochrome`] updates can still show shades of gray.
+///
+/// Any color that can be converted to [`Gray8`] is accepted, including RGB colors.
+pub struct Dithered<T, C = Gray8> {
+	inner: T,
+	mode: DitherMode,
+	_color: PhantomData<C>,
+}
+
+impl<T, C> Dithered<T, C> {
+	#[inline]
+	pub fn new(inner: T, mode: DitherMode) -> Self {
+		Self {
+			inner,
+			mode,
+			_color: PhantomData,
+		}
+	}
+
+	#[inline]
+	pub fn mode(&self) -> DitherMode {
+		self.mode
+	}
+
+	#[inline]
+	pub fn set_mode(&mut self, mode: DitherMode) {
+		self.mode = mode;
+	}
+
+	#[inline]
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+}
+
+impl<T: OriginDimensions, C> OriginDimensions for Dithered<T, C> {
+	fn size(&self) -> Size {
+		self.inner.size()
+	}
+}
+
+impl<T: DrawTarget + OriginDimensions, C: PixelColor + Into<Gray8>> DrawTarget for Dithered<T, C>
+where
+	Gray8: Into<T::Color>,
+{
+	type Color = C;
+
+	type Error = T::Error;
+
+	fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Pixel<Self::Color>>,
+	{
+		let to_inner = |white: bool| if white { Gray8::WHITE } else { Gray8::BLACK }.into();
+
+		match self.mode {
+			DitherMode::Bayer => self
+				.inner
+				.draw_iter(pixels.into_iter().map(|Pixel(point, color)| {
+					let (white, _) = quantize(luma(color), bayer_threshold(point));
+					Pixel(point, to_inner(white))
+				})),
+			DitherMode::FloydSteinberg => {
+				// The pixels can come in any order, so keep the diffused error by position.
+				let mut errors = HashMap::<Point, i16>::new();
+				self
+					.inner
+					.draw_iter(pixels.into_iter().map(|Pixel(point, color)| {
+						let value = luma(color) + errors.remove(&point).unwrap_or(0);
+						let (white, error) = quantize(value, 128);
+						for (offset, weight) in [((1, 0), 7), ((-1, 1), 3), ((0, 1), 5), ((1, 1), 1)] {
+							*errors.entry(point + Point::from(offset)).or_default() += error * weight / 16;
+						}
+						Pixel(point, to_inner(white))
+					}))
+			}
+		}
+	}
+
+	fn fill_contiguous<I>(&mut self, area: &BadRect, colors: I) -> Result<(), Self::Error>
+	where
+		I: IntoIterator<Item = Self::Color>,
+	{
+		let to_inner = |white: bool| if white { Gray8::WHITE } else { Gray8::BLACK }.into();
+		let width = area.size.width as usize;
+
+		match self.mode {
+			DitherMode::Bayer => self.inner.fill_contiguous(
+				area,
+				colors.into_iter().enumerate().map(|(i, color)| {
+					// Relative to the area, but the matrix tiles every 4 pixels anyway.
+					#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+					let point = area.top_left + Point::new((i % width) as i32, (i / width) as i32);
+					let (white, _) = quantize(luma(color), bayer_threshold(point));
+					to_inner(white)
+				}),
+			),
+			DitherMode::FloydSteinberg => {
+				// Padded by one on each side so that the error can be diffused past the edges.
+				let mut current = vec![0i16; width + 2];
+				let mut next = vec![0i16; width + 2];
+				self.inner.fill_contiguous(
+					area,
+					colors.into_iter().enumerate().map(|(i, color)| {
+						let x = i % width;
+						if i > 0 && x == 0 {
+							std::mem::swap(&mut current, &mut next);
+							next.fill(0);
+						}
+						let (white, error) = quantize(luma(color) + current[x + 1], 128);
+						current[x + 2] += error * 7 / 16;
+						next[x] += error * 3 / 16;
+						next[x + 1] += error * 5 / 16;
+						next[x + 2] += error / 16;
+						to_inner(white)
+					}),
+				)
+			}
+		}
+	}
+
+	fn fill_solid(&mut self, area: &BadRect, color: Self::Color) -> Result<(), Self::Error> {
+		match luma(color) {
+			0 => self.inner.fill_solid(area, Gray8::BLACK.into()),
+			255 => self.inner.fill_solid(area, Gray8::WHITE.into()),
+			_ => self.fill_contiguous(area, std::iter::repeat(color)),
+		}
+	}
+}
+
+mut_draw_target!(Dithered<T, C>: [T: DrawTarget + OriginDimensions, C: PixelColor + Into<Gray8>] where [Gray8: Into<T::Color>]);
+
+impl<T: EinkUpdate, C> EinkUpdate for Dithered<T, C> {
+	fn update(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		self.inner.update(area, style, depth, options)
+	}
+}
+
+impl<T: EinkUpdateAsync, C> EinkUpdateAsync for Dithered<T, C> {
+	fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> impl std::future::Future<Output = std::io::Result<UpdateHandle>> {
+		self.inner.update_async(area, style, depth, options)
+	}
+}
+
+#[test]
+fn test_coalescing() {
+	use rmox_common::eink_update::EinkUpdateExt as _;
+	use rmox_common::types::rect;
+
+	use crate::{Framebuffer, UpdateRecord};
+
+	let fb = Coalescing::new(Framebuffer::headless());
+	// Two adjacent lines.
+	fb.update_partial(&rect(0, 0, 100, 10), UpdateStyle::Monochrome)
+		.unwrap();
+	fb.update_partial(&rect(0, 10, 100, 10), UpdateStyle::Monochrome)
+		.unwrap();
+	// Overlapping the second line.
+	fb.update_partial(&rect(50, 15, 100, 10), UpdateStyle::Monochrome)
+		.unwrap();
+	// Separate.
+	fb.update_partial(&rect(0, 100, 10, 10), UpdateStyle::Monochrome)
+		.unwrap();
+	// Same area but a different style.
+	fb.update_partial(&rect(0, 0, 10, 10), UpdateStyle::Rgb)
+		.unwrap();
+	assert_eq!(fb.inner.take_update_log().unwrap(), []);
+
+	fb.commit().unwrap();
+	let log = fb.inner.take_update_log().unwrap();
+	let record = |area, style| UpdateRecord {
+		area,
+		style,
+		depth: UpdateDepth::Partial,
+		options: UpdateOptions::default(),
+	};
+	assert_eq!(log.len(), 3);
+	for expected in [
+		record(rect(0, 0, 150, 25), UpdateStyle::Monochrome),
+		record(rect(0, 100, 10, 10), UpdateStyle::Monochrome),
+		record(rect(0, 0, 10, 10), UpdateStyle::Rgb),
+	] {
+		assert!(log.contains(&expected), "{expected:?} not in {log:?}");
+	}
+
+	fb.commit().unwrap();
+	assert_eq!(fb.inner.take_update_log().unwrap(), []);
+}
+
+#[test]
+fn test_grayscale() {
+	use embedded_graphics_core::pixelcolor::Gray4;
+	use rmox_common::types::rect;
+
+	use crate::Framebuffer;
+
+	let mut fb = Grayscale::<_, Gray8>::new(Framebuffer::headless());
+	fb.clear(Gray8::WHITE).unwrap();
+	assert!(fb.inner.pixels().iter().all(|&pixel| pixel == 0xffff));
+
+	let mut fb = Grayscale::<_, Gray4>::new(fb.into_inner());
+	fb.fill_solid(&rect(0, 0, 2, 1).into(), Gray4::BLACK)
+		.unwrap();
+	fb.draw_iter([Pixel(Point::new(2, 0), Gray4::new(0x8))])
+		.unwrap();
+	let pixels = &fb.inner.pixels()[..4];
+	// 0x8 of 0xf is 17/31 in red and blue and 34/63 in green.
+	assert_eq!(pixels, [0x0000, 0x0000, 0x8c51, 0xffff]);
+}
+
+#[test]
+fn test_dithered() {
+	use rmox_common::types::rect;
+
+	use crate::Framebuffer;
+
+	let gray = Gray8::new(0x80);
+	let area = rect(0, 0, 8, 8).into();
+	for mode in [DitherMode::Bayer, DitherMode::FloydSteinberg] {
+		let mut fb = Dithered::new(Framebuffer::headless(), mode);
+		fb.fill_solid(&area, gray).unwrap();
+		let fb = fb.into_inner();
+		let width = usize::try_from(fb.width()).unwrap();
+		let pixels: Vec<u16> = (0..8)
+			.flat_map(|y| fb.pixels()[y * width..][..8].iter().copied())
+			.collect();
+		assert!(pixels.iter().all(|&pixel| pixel == 0 || pixel == 0xffff));
+		let white = pixels.iter().filter(|&&pixel| pixel == 0xffff).count();
+		assert!((28..=36).contains(&white), "{mode:?}: {white} white pixels");
 	}
 }
@@ -1,6 +1,6 @@
 #[cfg(feature = "input-impl")]
 use evdev::KeyCode;
-use rmox_common::types::{pos2, Pos2};
+use rmox_common::types::{pos2, Pos2, Vec2};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy)]
@@ -62,22 +62,24 @@ impl StylusState {
 		self.touching
 	}
 
+	/// The X position on a screen of `screen_size`, which the digitizer is stretched to cover.
 	#[inline]
 	#[must_use]
-	pub fn x(self) -> f32 {
-		f32::from(self.y) * (rmox_common::fb::WIDTH as f32 / 15725.0)
+	pub fn x(self, screen_size: Vec2) -> f32 {
+		f32::from(self.y) * (screen_size.x as f32 / 15725.0)
 	}
 
+	/// The Y position on a screen of `screen_size`, which the digitizer is stretched to cover.
 	#[inline]
 	#[must_use]
-	pub fn y(self) -> f32 {
-		rmox_common::fb::HEIGHT as f32 - f32::from(self.x) * (rmox_common::fb::HEIGHT as f32 / 20967.0)
+	pub fn y(self, screen_size: Vec2) -> f32 {
+		screen_size.y as f32 - f32::from(self.x) * (screen_size.y as f32 / 20967.0)
 	}
 
 	#[inline]
 	#[must_use]
-	pub fn position(self) -> Pos2 {
-		pos2(self.x() as i32, self.y() as i32)
+	pub fn position(self, screen_size: Vec2) -> Pos2 {
+		pos2(self.x(screen_size) as i32, self.y(screen_size) as i32)
 	}
 
 	#[inline]
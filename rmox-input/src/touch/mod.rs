@@ -1,6 +1,6 @@
 #[cfg(feature = "input-impl")]
 use evdev::EventSummary;
-use rmox_common::types::{pos2, Pos2};
+use rmox_common::types::{pos2, Pos2, Vec2};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy)]
@@ -31,13 +31,21 @@ pub struct TouchState {
 	orientation: i8,
 }
 
+/// The range of the touchscreen's axes, which is the size of the rM2's display.
+const RANGE: Vec2 = Vec2 {
+	x: rmox_common::fb::WIDTH,
+	y: rmox_common::fb::HEIGHT,
+};
+
 impl TouchState {
+	/// The position on a screen of `screen_size`, which the touchscreen is stretched to cover.
 	#[inline]
 	#[must_use]
-	pub fn position(&self) -> Pos2 {
+	pub fn position(&self, screen_size: Vec2) -> Pos2 {
+		let x = i32::from(self.x) * screen_size.x / RANGE.x;
 		// The Y is mirrored relative to the framebuffer.
-		let y = rmox_common::fb::HEIGHT - i32::from(self.y);
-		pos2(self.x.into(), y)
+		let y = screen_size.y - i32::from(self.y) * screen_size.y / RANGE.y;
+		pos2(x, y)
 	}
 
 	#[inline]
@@ -1,6 +1,13 @@
-use rmox_common::types::Side;
+//! The requests from clients to the WM.
+//!
+//! Changing how any of these types are encoded needs a bump of [`VERSION`](crate::handshake::VERSION).
+
+use rmox_common::eink_update::{UpdateDepth, UpdateStyle};
+use rmox_common::types::{Rectangle, Side};
 use serde::{Deserialize, Serialize};
 
+use crate::{Selection, Serial, SurfaceId, TransferId};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum SurfaceInit {
 	Layer { anchor: Side, size: i32 },
@@ -11,4 +18,78 @@ pub enum SurfaceInit {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
 	CreateSurface(SurfaceInit),
+	/// Destroy one of the task's surfaces.
+	/// The surface will receive `SurfaceEvent::Quit` as if the WM had removed it.
+	DestroySurface(SurfaceId),
+	/// List the task's surfaces.
+	ListSurfaces,
+	/// Show the back buffer of one of the task's surfaces by compositing it onto the screen.
+	/// Afterwards, both sides flip the surface's buffers.
+	///
+	/// The WM may read the committed buffer until it acks the commit.
+	/// The client can draw into its new back buffer in the meantime, but must not commit again before the ack,
+	/// since flipping after that commit would overwrite the buffer that the WM is reading.
+	///
+	/// Only the `damage` is composited and refreshed, using `style` and `depth`.
+	/// It is relative to the buffers, and clipped to them.
+	/// The WM owns all refreshes of the display, so this is the only way for a surface to be refreshed.
+	///
+	/// `generation` is that of the buffers from the latest `SurfaceEvent::Buffers`.
+	/// If the buffers were replaced while the commit was in flight, it fails with `CommandError::StaleBuffers`.
+	Commit {
+		surface: SurfaceId,
+		generation: u32,
+		damage: Vec<Rectangle>,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+	},
+	/// Set or clear the human-readable title of one of the task's surfaces.
+	SetTitle {
+		surface: SurfaceId,
+		title: Option<Box<str>>,
+	},
+	/// Set or clear the identifier of the application that owns one of the task's surfaces.
+	SetAppId {
+		surface: SurfaceId,
+		app_id: Option<Box<str>>,
+	},
+	/// Become the owner of `selection`, offering its data in each of `mime_types`.
+	/// The previous owner, if any, receives `Event::SelectionCancelled`.
+	/// Requests for the data arrive as `Event::SelectionRequest`.
+	OfferSelection {
+		selection: Selection,
+		mime_types: Vec<Box<str>>,
+	},
+	/// Get the data of `selection` as `mime_type`, which must be one that the owner offered.
+	/// The WM forwards the request to the owner, so the reply may take a while; it is `Reply::SelectionData`.
+	RequestSelection {
+		selection: Selection,
+		mime_type: Box<str>,
+	},
+	/// Answer an `Event::SelectionRequest`, or decline it with `None`.
+	SendSelection {
+		transfer: TransferId,
+		#[serde(with = "serde_bytes")]
+		data: Option<Vec<u8>>,
+	},
+	/// Capture the whole screen, or only `surface`, as a PNG image.
+	/// Any surface can be captured, not just the task's own, and the image is in the orientation that it is displayed in.
+	/// The reply is `Reply::Screenshot`.
+	Screenshot {
+		surface: Option<SurfaceId>,
+	},
+	/// Make one of the task's normal surfaces cover the whole screen, or return it to its place in the layout.
+	/// A surface that becomes fullscreen is also focused, and it stops being fullscreen when it loses focus.
+	RequestFullscreen {
+		surface: SurfaceId,
+		fullscreen: bool,
+	},
+}
+
+/// A command along with its serial.
+/// The server replies to every request with an `Event::Ack` carrying the same serial.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+	pub serial: Serial,
+	pub command: Command,
 }
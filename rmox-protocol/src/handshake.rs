@@ -0,0 +1,250 @@
+//! The handshake at the start of every connection, before any commands or events are exchanged.
+//!
+//! The client sends a [`Hello`] and the server replies with a [`HelloReply`].
+//! Unlike the rest of the protocol, these types must stay compatible across versions,
+//! so that a mismatched client and server can at least tell each other why they can't communicate.
+
+use enumset::{EnumSet, EnumSetType};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::StreamExt as _;
+
+use crate::client_to_server::Request;
+use crate::io::Stream;
+use crate::server_to_client::Event;
+
+/// The version of the protocol implemented by this crate.
+///
+/// Clients and servers can only communicate if their versions are equal,
+/// so this must be bumped by every change to the encoding of requests or events,
+/// unless the change is only used when a [`Capability`] for it was negotiated.
+///
+/// 1. The handshake.
+/// 2. Double-buffered surfaces, along with everything added since 1 without a bump:
+///    serials and acks, surface destruction and listing, titles and app ids, selections, and screenshots.
+/// 3. Damage, style and depth in commits, along with fullscreen requests.
+/// 4. Buffer generations.
+pub const VERSION: u32 = 4;
+
+/// Optional features of the protocol.
+///
+/// The server will only use features that both sides support.
+#[derive(Debug, EnumSetType)]
+#[enumset(no_ops, serialize_repr = "u64")]
+pub enum Capability {
+	/// `SurfaceEvent::FocusIn` and `SurfaceEvent::FocusOut`.
+	FocusEvents,
+	/// `Event::FocusedSurface`, e.g., for a bar to show the title of the focused surface.
+	FocusedSurfaceInfo,
+}
+
+pub type Capabilities = EnumSet<Capability>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+	pub version: u32,
+	pub capabilities: Capabilities,
+}
+
+impl Hello {
+	#[inline]
+	#[must_use]
+	pub fn new(capabilities: Capabilities) -> Self {
+		Self {
+			version: VERSION,
+			capabilities,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HelloReply {
+	/// The server accepted the client.
+	/// The capabilities are those supported by both sides.
+	Accept(Hello),
+	/// The server rejected the client and will close the connection.
+	Reject(Rejection),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+	UnsupportedVersion { client: u32, server: u32 },
+}
+
+impl std::fmt::Display for Rejection {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::UnsupportedVersion { client, server } => write!(
+				f,
+				"client protocol version {client} is not supported by server protocol version {server}"
+			),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum Error {
+	Io(std::io::Error),
+	Decode(ciborium::de::Error<std::io::Error>),
+	/// The connection was closed before the handshake completed.
+	Closed,
+	Rejected(Rejection),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(error) => write!(f, "I/O error during handshake: {error}"),
+			Self::Decode(error) => write!(f, "decoding handshake message: {error}"),
+			Self::Closed => f.write_str("connection closed during handshake"),
+			Self::Rejected(rejection) => write!(f, "rejected by server: {rejection}"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+async fn read<T, ReadItem, WriteItem>(
+	stream: &mut Stream<T, ReadItem, WriteItem>,
+) -> Result<ReadItem, Error>
+where
+	T: AsyncRead + Unpin,
+	ReadItem: serde::de::DeserializeOwned + Unpin,
+	WriteItem: Unpin,
+{
+	stream
+		.next()
+		.await
+		.ok_or(Error::Closed)?
+		.map_err(Error::Decode)
+}
+
+/// Perform the client side of the handshake, returning the stream for the rest of the protocol and the negotiated capabilities.
+///
+/// # Errors
+///
+/// - Reading from or writing to `inner`
+/// - The server rejecting the client
+pub async fn client<T: AsyncRead + AsyncWrite + Unpin>(
+	inner: T,
+	capabilities: Capabilities,
+) -> Result<(Stream<T, Event, Request>, Capabilities), Error> {
+	let mut stream = Stream::<T, HelloReply, Hello>::new(inner);
+	stream
+		.write(&Hello::new(capabilities))
+		.await
+		.map_err(Error::Io)?;
+	match read(&mut stream).await? {
+		HelloReply::Accept(hello) => Ok((stream.cast(), hello.capabilities)),
+		HelloReply::Reject(rejection) => Err(Error::Rejected(rejection)),
+	}
+}
+
+/// Perform the server side of the handshake, returning the stream for the rest of the protocol and the negotiated capabilities.
+///
+/// Incompatible clients are sent a [`HelloReply::Reject`] before this returns an error.
+///
+/// # Errors
+///
+/// - Reading from or writing to `inner`
+/// - The client being incompatible
+pub async fn server<T: AsyncRead + AsyncWrite + Unpin>(
+	inner: T,
+	capabilities: Capabilities,
+) -> Result<(Stream<T, Request, Event>, Capabilities), Error> {
+	let mut stream = Stream::<T, Hello, HelloReply>::new(inner);
+	let hello = read(&mut stream).await?;
+
+	if hello.version != VERSION {
+		let rejection = Rejection::UnsupportedVersion {
+			client: hello.version,
+			server: VERSION,
+		};
+		stream
+			.write(&HelloReply::Reject(rejection))
+			.await
+			.map_err(Error::Io)?;
+		return Err(Error::Rejected(rejection));
+	}
+
+	let capabilities = capabilities.intersection(hello.capabilities);
+	stream
+		.write(&HelloReply::Accept(Hello::new(capabilities)))
+		.await
+		.map_err(Error::Io)?;
+	Ok((stream.cast(), capabilities))
+}
+
+#[tokio::test]
+async fn test_handshake_accept() {
+	use Capability::{FocusEvents, FocusedSurfaceInfo};
+
+	for (client_capabilities, server_capabilities, negotiated) in [
+		(FocusEvents.into(), Capabilities::all(), FocusEvents.into()),
+		(
+			Capabilities::all(),
+			FocusedSurfaceInfo.into(),
+			FocusedSurfaceInfo.into(),
+		),
+		(
+			FocusEvents.into(),
+			FocusedSurfaceInfo.into(),
+			Capabilities::empty(),
+		),
+	] {
+		let (client_io, server_io) = tokio::io::duplex(64);
+		let (client, server) = tokio::join!(
+			client(client_io, client_capabilities),
+			server(server_io, server_capabilities),
+		);
+		// Both sides agree on the capabilities that they both support.
+		assert_eq!(client.unwrap().1, negotiated);
+		assert_eq!(server.unwrap().1, negotiated);
+	}
+}
+
+#[tokio::test]
+async fn test_handshake_reject() {
+	let (client_io, server_io) = tokio::io::duplex(64);
+	let mut client = Stream::<_, HelloReply, Hello>::new(client_io);
+	client
+		.write(&Hello {
+			version: VERSION + 1,
+			capabilities: Capabilities::all(),
+		})
+		.await
+		.unwrap();
+
+	let rejection = Rejection::UnsupportedVersion {
+		client: VERSION + 1,
+		server: VERSION,
+	};
+	let res = server(server_io, Capabilities::all()).await;
+	assert!(matches!(res, Err(Error::Rejected(other)) if other == rejection));
+	// The client is told why before the connection is closed.
+	assert_eq!(
+		client.next().await.unwrap().unwrap(),
+		HelloReply::Reject(rejection),
+	);
+	assert!(client.next().await.is_none());
+}
+
+#[tokio::test]
+async fn test_handshake_client_rejected() {
+	let (client_io, server_io) = tokio::io::duplex(64);
+	let mut server = Stream::<_, Hello, HelloReply>::new(server_io);
+	let rejection = Rejection::UnsupportedVersion {
+		client: VERSION,
+		server: VERSION + 1,
+	};
+	let respond = async {
+		assert_eq!(
+			server.next().await.unwrap().unwrap(),
+			Hello::new(Capabilities::all()),
+		);
+		server.write(&HelloReply::Reject(rejection)).await.unwrap();
+	};
+
+	let (res, ()) = tokio::join!(client(client_io, Capabilities::all()), respond);
+	assert!(matches!(res, Err(Error::Rejected(other)) if other == rejection));
+}
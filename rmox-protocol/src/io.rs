@@ -1,27 +1,194 @@
 //! Implements a simple message protocol where messages are a little-endian u32 of the payload length followed by a CBOR payload.
+//!
+//! Over a [`UnixTransport`], file descriptors can also be sent alongside messages.
 
+use std::collections::VecDeque;
 use std::marker::PhantomData;
+use std::os::fd::{AsFd as _, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd, RawFd};
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt as _, Interest, ReadBuf};
+use tokio::net::UnixStream;
 
-async fn write<T: AsyncWrite + Unpin, Item: Serialize + ?Sized>(
-	mut writer: T,
-	message: &Item,
-) -> std::io::Result<()> {
+fn encode<Item: Serialize + ?Sized>(message: &Item) -> Vec<u8> {
 	let mut ret = vec![0u8; 4];
 	ciborium::into_writer(message, &mut ret).unwrap();
 
 	let size: u32 = (ret.len() - 4).try_into().unwrap();
 	ret[0..4].copy_from_slice(&size.to_le_bytes());
 
-	writer.write_all(&ret).await?;
+	ret
+}
+
+async fn write<T: AsyncWrite + Unpin, Item: Serialize + ?Sized>(
+	mut writer: T,
+	message: &Item,
+) -> std::io::Result<()> {
+	writer.write_all(&encode(message)).await?;
 	Ok(())
 }
 
+/// The most file descriptors that can be sent with a single message.
+pub const MAX_FDS: usize = 4;
+
+/// Enough for a `cmsghdr` followed by `MAX_FDS` descriptors, aligned for the header.
+type ControlBuf = [u64; 8];
+
+fn recv_with_fds(
+	socket: BorrowedFd<'_>,
+	buf: &mut [u8],
+	fds: &mut VecDeque<OwnedFd>,
+) -> std::io::Result<usize> {
+	let mut iov = libc::iovec {
+		iov_base: buf.as_mut_ptr().cast(),
+		iov_len: buf.len(),
+	};
+	let mut control = ControlBuf::default();
+	// SAFETY: All-zero is a valid `msghdr`.
+	let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+	msg.msg_iov = &mut iov;
+	msg.msg_iovlen = 1;
+	msg.msg_control = control.as_mut_ptr().cast();
+	msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+	// SAFETY: The buffers referenced by `msg` are valid for the duration of the call.
+	let read = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+	let read = usize::try_from(read).map_err(|_| std::io::Error::last_os_error())?;
+
+	// SAFETY: `msg` was filled in by `recvmsg`, so the control messages are well-formed.
+	unsafe {
+		let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+		while !cmsg.is_null() {
+			if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+				let data = libc::CMSG_DATA(cmsg);
+				let data_len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+				for i in 0..data_len / std::mem::size_of::<RawFd>() {
+					let fd = data.cast::<RawFd>().add(i).read_unaligned();
+					// We now own the received descriptor.
+					fds.push_back(OwnedFd::from_raw_fd(fd));
+				}
+			}
+			cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+		}
+	}
+
+	if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"received too many file descriptors",
+		));
+	}
+
+	Ok(read)
+}
+
+fn send_with_fds(
+	socket: BorrowedFd<'_>,
+	buf: &[u8],
+	fds: &[BorrowedFd<'_>],
+) -> std::io::Result<usize> {
+	assert!(fds.len() <= MAX_FDS, "too many file descriptors");
+
+	let mut iov = libc::iovec {
+		iov_base: buf.as_ptr().cast_mut().cast(),
+		iov_len: buf.len(),
+	};
+	let mut control = ControlBuf::default();
+	// SAFETY: All-zero is a valid `msghdr`.
+	let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+	msg.msg_iov = &mut iov;
+	msg.msg_iovlen = 1;
+
+	if !fds.is_empty() {
+		let data_len = u32::try_from(std::mem::size_of_val(fds)).unwrap();
+		msg.msg_control = control.as_mut_ptr().cast();
+		// SAFETY: Just a calculation.
+		msg.msg_controllen = unsafe { libc::CMSG_SPACE(data_len) } as _;
+		// SAFETY: `control` is aligned and large enough for one `cmsghdr` with `MAX_FDS` descriptors.
+		unsafe {
+			let cmsg = libc::CMSG_FIRSTHDR(&msg);
+			(*cmsg).cmsg_level = libc::SOL_SOCKET;
+			(*cmsg).cmsg_type = libc::SCM_RIGHTS;
+			(*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+			let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+			for (i, fd) in fds.iter().enumerate() {
+				data.add(i).write_unaligned(fd.as_raw_fd());
+			}
+		}
+	}
+
+	// SAFETY: The buffers referenced by `msg` are valid for the duration of the call.
+	let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+	usize::try_from(sent).map_err(|_| std::io::Error::last_os_error())
+}
+
+/// A Unix socket that can pass file descriptors alongside messages.
+///
+/// Received descriptors are queued in the order they arrive.
+/// They can be taken with [`Stream::take_fd`] once the message they were sent with has been read.
+#[derive(Debug)]
+pub struct UnixTransport {
+	inner: UnixStream,
+	fds: VecDeque<OwnedFd>,
+}
+
+impl UnixTransport {
+	#[inline]
+	#[must_use]
+	pub fn new(inner: UnixStream) -> Self {
+		Self {
+			inner,
+			fds: VecDeque::new(),
+		}
+	}
+}
+
+impl AsyncRead for UnixTransport {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<std::io::Result<()>> {
+		let this = self.get_mut();
+		loop {
+			ready!(this.inner.poll_read_ready(cx))?;
+			let unfilled = buf.initialize_unfilled();
+			let res = this.inner.try_io(Interest::READABLE, || {
+				recv_with_fds(this.inner.as_fd(), unfilled, &mut this.fds)
+			});
+			match res {
+				Ok(read) => {
+					buf.advance(read);
+					return Poll::Ready(Ok(()));
+				}
+				Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => continue,
+				Err(error) => return Poll::Ready(Err(error)),
+			}
+		}
+	}
+}
+
+impl AsyncWrite for UnixTransport {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<std::io::Result<usize>> {
+		Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
 enum ReadState {
 	Start,
 	Size(u32),
@@ -46,6 +213,18 @@ impl<T, ReadItem, WriteItem> Stream<T, ReadItem, WriteItem> {
 			_items: PhantomData,
 		}
 	}
+
+	/// Change the types of the messages read from and written to this stream, e.g., after a handshake.
+	///
+	/// Any partially read message is preserved.
+	pub fn cast<NewReadItem, NewWriteItem: ?Sized>(self) -> Stream<T, NewReadItem, NewWriteItem> {
+		Stream {
+			inner: self.inner,
+			buf: self.buf,
+			read_state: self.read_state,
+			_items: PhantomData,
+		}
+	}
 }
 
 struct LenGuard<'a> {
@@ -149,3 +328,80 @@ where
 		write(&mut self.inner, message).await
 	}
 }
+
+impl<ReadItem, WriteItem> Stream<UnixTransport, ReadItem, WriteItem>
+where
+	WriteItem: Serialize + ?Sized,
+{
+	/// Like [`Self::write`], but also sends `fds`, which the other side can take with [`Self::take_fd`] after reading the message.
+	///
+	/// # Panics
+	///
+	/// If there are more than [`MAX_FDS`] descriptors.
+	pub async fn write_with_fds(
+		&mut self,
+		message: &WriteItem,
+		fds: &[BorrowedFd<'_>],
+	) -> std::io::Result<()> {
+		let buf = encode(message);
+		// The descriptors are attached to the first part of the message, so they arrive no later than the message itself.
+		let socket = &self.inner.inner;
+		let sent = socket
+			.async_io(Interest::WRITABLE, || {
+				send_with_fds(socket.as_fd(), &buf, fds)
+			})
+			.await?;
+		self.inner.write_all(&buf[sent..]).await
+	}
+}
+
+impl<ReadItem, WriteItem: ?Sized> Stream<UnixTransport, ReadItem, WriteItem> {
+	/// Take the oldest file descriptor that was received and not yet taken.
+	///
+	/// Descriptors are received along with the messages they were sent with,
+	/// so one that was sent with a message is available once that message has been read.
+	#[inline]
+	pub fn take_fd(&mut self) -> Option<OwnedFd> {
+		self.inner.fds.pop_front()
+	}
+}
+
+#[tokio::test]
+async fn test_fd_passing() {
+	use std::io::{Read as _, Seek as _, Write as _};
+
+	use tokio_stream::StreamExt as _;
+
+	let (a, b) = UnixStream::pair().unwrap();
+	let mut a = Stream::<_, (), String>::new(UnixTransport::new(a));
+	let mut b = Stream::<_, String, ()>::new(UnixTransport::new(b));
+
+	let mut file = tempfile().unwrap();
+	file.write_all(b"hello").unwrap();
+	a.write(&"before".to_owned()).await.unwrap();
+	a.write_with_fds(&"with fd".to_owned(), &[file.as_fd()])
+		.await
+		.unwrap();
+	drop(file);
+
+	assert_eq!(b.next().await.unwrap().unwrap(), "before");
+	assert!(b.take_fd().is_none());
+	assert_eq!(b.next().await.unwrap().unwrap(), "with fd");
+	let mut file = std::fs::File::from(b.take_fd().unwrap());
+	let mut contents = String::new();
+	file.rewind().unwrap();
+	file.read_to_string(&mut contents).unwrap();
+	assert_eq!(contents, "hello");
+	assert!(b.take_fd().is_none());
+}
+
+#[cfg(test)]
+fn tempfile() -> std::io::Result<std::fs::File> {
+	// SAFETY: The name is a valid C string.
+	let fd = unsafe { libc::memfd_create(c"test".as_ptr(), libc::MFD_CLOEXEC) };
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	// SAFETY: We own the new descriptor.
+	Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
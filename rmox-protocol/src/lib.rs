@@ -10,6 +10,21 @@ pub struct Id(NonZeroU32);
 pub struct SurfaceId(pub Id);
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TaskId(pub Id);
+/// Chosen by the client for each command so it can correlate the server's reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Serial(pub u32);
+/// Identifies a request for selection data that the WM forwarded to the selection's owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TransferId(pub Id);
+
+/// A system-wide slot that a client can offer data in, for other clients to paste.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Selection {
+	/// Data that was explicitly copied.
+	Clipboard,
+	/// Data that is currently selected, e.g., highlighted text.
+	Primary,
+}
 
 impl Id {
 	pub const START: Self = Self(match NonZeroU32::new(1) {
@@ -24,6 +39,7 @@ impl Id {
 }
 
 pub mod client_to_server;
+pub mod handshake;
 pub mod server_to_client;
 
 pub mod server {
@@ -1,13 +1,20 @@
+//! The events from the WM to clients.
+//!
+//! Changing how any of these types are encoded needs a bump of [`VERSION`](crate::handshake::VERSION).
+
 use embedded_graphics_core::draw_target::DrawTarget;
 use embedded_graphics_core::geometry::{OriginDimensions, Size};
 use embedded_graphics_core::primitives::Rectangle as BadRect;
 use embedded_graphics_core::Pixel;
-use rmox_common::eink_update::{EinkUpdate, UpdateDepth, UpdateStyle};
+use rmox_common::eink_update::{
+	EinkUpdate, EinkUpdateAsync, UpdateDepth, UpdateHandle, UpdateOptions, UpdateStyle,
+};
 use rmox_common::mut_draw_target;
 use rmox_common::types::{Pos2, Rectangle, Rotation, Vec2};
 use serde::{Deserialize, Serialize};
 
-use crate::SurfaceId;
+use crate::handshake::Capability;
+use crate::{Selection, Serial, SurfaceId, TransferId};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SurfaceDescription {
@@ -27,6 +34,17 @@ impl SurfaceDescription {
 		point
 	}
 
+	/// The inverse of [`Self::transform_point`], mapping a point on the framebuffer into the surface's logical coordinate space.
+	#[inline]
+	#[must_use]
+	pub fn inverse_transform_point(&self, mut point: Pos2) -> Pos2 {
+		point -= self.base_rect.origin.to_vec();
+		let rotated_size = self.rotation.transform_size(self.base_rect.size).abs();
+		point = self.rotation.inverse().transform_point(point, rotated_size);
+		point /= self.scale.into();
+		point
+	}
+
 	#[inline]
 	pub fn transform_rect(&self, mut rect: Rectangle) -> Rectangle {
 		let scale: i32 = self.scale.into();
@@ -41,6 +59,16 @@ impl SurfaceDescription {
 		rect.intersection(&self.base_rect)
 	}
 
+	/// The description for drawing into the surface's buffers, which cover `base_rect` but start at the origin.
+	#[inline]
+	#[must_use]
+	pub fn buffer_description(&self) -> Self {
+		Self {
+			base_rect: Rectangle::new(Pos2::ZERO, self.base_rect.size),
+			..*self
+		}
+	}
+
 	#[inline]
 	#[must_use]
 	pub fn size(&self) -> Vec2 {
@@ -75,6 +103,23 @@ fn test_transform_point() {
 	assert_eq!(desc.transform_point(pos2(10, 20)), pos2(240, 980));
 }
 
+#[test]
+fn test_inverse_transform_point() {
+	use rmox_common::types::{pos2, rect};
+
+	let desc = SurfaceDescription {
+		base_rect: rect(200, 200, 500, 800),
+		rotation: Rotation::Rotate270,
+		scale: 2,
+		visible: true,
+	};
+	assert_eq!(desc.inverse_transform_point(pos2(200, 1000)), pos2(0, 0));
+	assert_eq!(desc.inverse_transform_point(pos2(200, 980)), pos2(10, 0));
+	assert_eq!(desc.inverse_transform_point(pos2(240, 980)), pos2(10, 20));
+	// Points within a scaled pixel map to that pixel.
+	assert_eq!(desc.inverse_transform_point(pos2(241, 979)), pos2(10, 20));
+}
+
 #[test]
 fn test_transform_rect() {
 	use rmox_common::types::rect;
@@ -149,18 +194,57 @@ impl<T: EinkUpdate> EinkUpdate for Transformed<'_, T> {
 		area: &Rectangle,
 		style: UpdateStyle,
 		depth: UpdateDepth,
-	) -> std::io::Result<()> {
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
+		let area = self.description.transform_rect(*area);
+		self.base.update(&area, style, depth, options)
+	}
+}
+
+impl<T: EinkUpdateAsync> EinkUpdateAsync for Transformed<'_, T> {
+	async fn update_async(
+		&self,
+		area: &Rectangle,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		options: UpdateOptions,
+	) -> std::io::Result<UpdateHandle> {
 		let area = self.description.transform_rect(*area);
-		self.base.update(&area, style, depth)
+		self.base.update_async(&area, style, depth, options).await
 	}
 }
 
 mut_draw_target!(Transformed<'a, T>: ['a, T: OriginDimensions + DrawTarget]);
 
+/// The state of a touch, with its position relative to the surface receiving it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TouchState {
+	/// The position of the touch in the surface's logical coordinate space.
+	pub position: Pos2,
+	/// The raw state as reported by the touchscreen, e.g., for pressure.
+	/// Note that the position of this state must be mapped to the screen's size, e.g., with `raw.position(screen_size)`.
+	pub raw: rmox_input::touch::TouchState,
+}
+
+impl TouchState {
+	#[inline]
+	#[must_use]
+	pub fn new(
+		raw: rmox_input::touch::TouchState,
+		screen_size: Vec2,
+		description: &SurfaceDescription,
+	) -> Self {
+		Self {
+			position: description.inverse_transform_point(raw.position(screen_size)),
+			raw,
+		}
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TouchPhase {
-	Start(rmox_input::touch::TouchState),
-	Change(rmox_input::touch::TouchState),
+	Start(TouchState),
+	Change(TouchState),
 	End,
 }
 
@@ -170,12 +254,39 @@ pub struct TouchEvent {
 	pub phase: TouchPhase,
 }
 
+/// The state of the stylus, with its position relative to the surface receiving it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StylusState {
+	/// The position of the stylus in the surface's logical coordinate space.
+	pub position: Pos2,
+	/// The raw state as reported by the digitizer, e.g., for pressure and tilt.
+	/// Note that the position of this state must be mapped to the screen's size, e.g., with `raw.position(screen_size)`.
+	pub raw: rmox_input::stylus::StylusState,
+}
+
+impl StylusState {
+	#[inline]
+	#[must_use]
+	pub fn new(
+		raw: rmox_input::stylus::StylusState,
+		screen_size: Vec2,
+		description: &SurfaceDescription,
+	) -> Self {
+		Self {
+			position: description.inverse_transform_point(raw.position(screen_size)),
+			raw,
+		}
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum StylusPhase {
-	Hover(rmox_input::stylus::StylusState),
-	Touch(rmox_input::stylus::StylusState),
-	Change(rmox_input::stylus::StylusState),
-	Lift(rmox_input::stylus::StylusState),
+	/// The stylus entered the surface without touching it, either by coming into range or by moving over from another surface.
+	Hover(StylusState),
+	Touch(StylusState),
+	Change(StylusState),
+	Lift(StylusState),
+	/// The stylus left the surface, either by going out of range or by moving over to another surface.
 	Leave,
 }
 
@@ -186,7 +297,6 @@ pub struct StylusEvent {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum InputEvent {
-	// TODO: Keyboard focus enter and leave events, similar to wayland.
 	Key(rmox_input::keyboard::KeyEvent),
 	Text(Box<str>),
 	Touch(TouchEvent),
@@ -199,11 +309,112 @@ pub enum InputEvent {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SurfaceEvent {
 	Description(SurfaceDescription),
+	/// New buffers for the surface to draw into, e.g., because its size changed.
+	///
+	/// The shared memory is sent as a file descriptor along with this event,
+	/// to be opened with `rmox_fb::SurfaceBuffers::open` using `size`, which is that of `base_rect`.
+	/// They replace any previous buffers, and are only shown once committed with `Command::Commit`.
+	/// This is sent before the `Description` with the new size.
+	Buffers {
+		size: Vec2,
+		/// Identifies these buffers in `Command::Commit`, since commits for the previous ones may still be in flight.
+		generation: u32,
+	},
 	Quit,
 	Input(InputEvent),
+	/// The surface gained keyboard focus.
+	FocusIn,
+	/// The surface lost keyboard focus.
+	FocusOut,
+}
+
+/// Metadata about a surface set by its client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SurfaceInfo {
+	pub title: Option<Box<str>>,
+	pub app_id: Option<Box<str>>,
+}
+
+/// The result of a successful command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reply {
+	/// The command succeeded with nothing to report.
+	Done,
+	SurfaceCreated(SurfaceId),
+	/// The task's surfaces, in no particular order.
+	Surfaces(Vec<SurfaceId>),
+	/// The data requested with `Command::RequestSelection`.
+	SelectionData(#[serde(with = "serde_bytes")] Vec<u8>),
+	/// The PNG image requested with `Command::Screenshot`.
+	Screenshot(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// The reason a command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommandError {
+	/// The size of a layer surface must be positive.
+	InvalidLayerSize,
+	/// The surface does not exist or, if the command modifies it, is not owned by the task.
+	UnknownSurface,
+	/// The selection is empty or was not offered in the requested MIME type.
+	NoSelection,
+	/// The owner of the selection declined the request or disconnected before answering.
+	SelectionUnavailable,
+	/// The transfer does not exist or was not sent to the task, or its requester disconnected.
+	UnknownTransfer,
+	/// The surface has no buffers to commit, because it has not been given an area yet.
+	NoBuffers,
+	/// The commit was for buffers that have since been replaced or do not match the surface's area, so neither side flips.
+	StaleBuffers,
+	/// The surface to capture is not visible.
+	SurfaceNotVisible,
+	/// The screenshot could not be encoded, e.g., because the surface has no area.
+	ScreenshotFailed,
+	/// Only normal surfaces can be made fullscreen.
+	NotNormalSurface,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Event {
-	Surface { id: SurfaceId, event: SurfaceEvent },
+	Surface {
+		id: SurfaceId,
+		event: SurfaceEvent,
+	},
+	/// The reply to the request with the same `serial`.
+	Ack {
+		serial: Serial,
+		result: Result<Reply, CommandError>,
+	},
+	/// Information about the surface with keyboard focus, if any.
+	/// Sent when the client connects and whenever the focused surface or its info changes.
+	FocusedSurface(Option<SurfaceInfo>),
+	/// Another client requested the data of a selection that this client owns.
+	/// The client must answer with `Command::SendSelection`.
+	SelectionRequest {
+		transfer: TransferId,
+		selection: Selection,
+		mime_type: Box<str>,
+	},
+	/// Another client took ownership of `selection`.
+	SelectionCancelled {
+		selection: Selection,
+	},
+}
+
+impl Event {
+	/// The capability that a client must have negotiated to be sent this event, if any.
+	#[must_use]
+	pub fn capability(&self) -> Option<Capability> {
+		match self {
+			Self::Surface {
+				event: SurfaceEvent::FocusIn | SurfaceEvent::FocusOut,
+				..
+			} => Some(Capability::FocusEvents),
+			Self::FocusedSurface(..) => Some(Capability::FocusedSurfaceInfo),
+			Self::Surface { .. }
+			| Self::Ack { .. }
+			| Self::SelectionRequest { .. }
+			| Self::SelectionCancelled { .. } => None,
+		}
+	}
 }
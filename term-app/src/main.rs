@@ -1,25 +1,29 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
 use alacritty_terminal::grid::Indexed;
 use alacritty_terminal::term::cell::Cell;
-use alacritty_terminal::term::{RenderableCursor, TermDamage};
+use alacritty_terminal::term::{ClipboardType, RenderableCursor, TermDamage};
 use alacritty_terminal::Term;
 use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::geometry::Dimensions;
 use embedded_graphics::mono_font::{ascii as fonts, MonoTextStyle};
-use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::pixelcolor::{Gray8, GrayColor as _};
 use embedded_graphics::text::{Baseline, Text};
 use embedded_graphics::Drawable as _;
-use rmox_common::eink_update::{EinkUpdateExt as _, UpdateStyle};
+use rmox_common::eink_update::{EinkUpdateExt as _, UpdateDepth, UpdateStyle};
 use rmox_common::types::{vec2, Rectangle, Vec2};
-use rmox_fb::util::Scaled;
-use rmox_fb::Framebuffer;
+use rmox_fb::util::{Coalescing, Grayscale, Scaled};
+use rmox_fb::{Framebuffer, SurfaceBuffers};
 use rmox_input::keyboard::Key;
 use rmox_protocol::client::recv::{
-	Event, InputEvent, SurfaceDescription, SurfaceEvent, Transformed,
+	Event, InputEvent, Reply, SurfaceDescription, SurfaceEvent, Transformed,
 };
-use rmox_protocol::client::send::{Command, SurfaceInit};
+use rmox_protocol::client::send::{Command, Request, SurfaceInit};
+use rmox_protocol::handshake::Capabilities;
+use rmox_protocol::io::UnixTransport;
+use rmox_protocol::{Selection, Serial};
 use tokio::time::Instant;
 use tokio::{pin, select};
 use tokio_stream::StreamExt as _;
@@ -33,6 +37,16 @@ struct TermDimensions {
 
 const SCROLLBACK: usize = 10_000;
 
+/// The only format that the terminal offers and requests selections in.
+const TEXT_MIME_TYPE: &str = "text/plain;charset=utf-8";
+
+fn clipboard_type_to_selection(ty: ClipboardType) -> Selection {
+	match ty {
+		ClipboardType::Clipboard => Selection::Clipboard,
+		ClipboardType::Selection => Selection::Primary,
+	}
+}
+
 impl TermDimensions {
 	pub fn from_vec2(size: Vec2) -> Self {
 		Self {
@@ -87,18 +101,30 @@ async fn main() {
 	let socket = tokio::net::UnixStream::connect(&socket_path)
 		.await
 		.unwrap_or_else(|error| panic!("connecting to {socket_path:?} (RMOX_SOCKET): {error}"));
-	let socket = rmox_protocol::io::Stream::new(socket);
+	let socket = UnixTransport::new(socket);
+	let (socket, _) = rmox_protocol::handshake::client(socket, Capabilities::empty())
+		.await
+		.unwrap_or_else(|error| panic!("handshake with WM: {error}"));
 	pin!(socket);
 
+	let mut serials = (0..).map(Serial);
+	let create_serial = serials.next().unwrap();
 	socket
-		.write(&Command::CreateSurface(SurfaceInit::Normal))
+		.write(&Request {
+			serial: create_serial,
+			command: Command::CreateSurface(SurfaceInit::Normal),
+		})
 		.await
 		.unwrap();
 
-	let mut fb = Framebuffer::open().unwrap();
+	let mut fb: Option<Framebuffer> = None;
+	let mut buffers_generation = 0;
+	// The serial of the commit that the WM has not acknowledged yet, and whether more was drawn since.
+	let mut pending_commit = None;
+	let mut needs_commit = false;
 
-	let fg = Rgb565::new(0, 0, 0);
-	let bg = Rgb565::new(31, 63, 31);
+	let fg = Gray8::BLACK;
+	let bg = Gray8::WHITE;
 
 	alacritty_terminal::tty::setup_env();
 
@@ -135,18 +161,102 @@ async fn main() {
 
 	let mut desc = None;
 	let mut old_cursor = None;
+	let mut surface_id = None;
+	// Kept so that a title set before the surface is created can be sent once it is.
+	let mut title: Option<Box<str>> = None;
+	// The text of the selections that this terminal owns.
+	let mut selections: HashMap<Selection, String> = HashMap::new();
+	// Pastes waiting for selection data, by the serial of their `RequestSelection` command.
+	let mut pending_loads: HashMap<Serial, Arc<dyn Fn(&str) -> String + Send + Sync>> =
+		HashMap::new();
 
 	// Intentionally create an elapsed sleep.
 	let pty_debounce = tokio::time::sleep_until(Instant::now() - Duration::from_secs(1));
 	pin!(pty_debounce);
 	loop {
+		// The WM may still be reading the last committed buffer, which flipping after another commit would overwrite.
+		if needs_commit && pending_commit.is_none() {
+			if let (Some(surface_fb), Some(surface)) = (&mut fb, surface_id) {
+				// The updates were only recorded, so they tell the WM what to refresh.
+				let damage: Vec<_> = surface_fb
+					.take_update_log()
+					.unwrap()
+					.into_iter()
+					.map(|update| update.area)
+					.collect();
+				let serial = serials.next().unwrap();
+				socket
+					.write(&Request {
+						serial,
+						command: Command::Commit {
+							surface,
+							generation: buffers_generation,
+							damage: damage.clone(),
+							style: UpdateStyle::Monochrome,
+							depth: UpdateDepth::Partial,
+						},
+					})
+					.await
+					.unwrap();
+				surface_fb.swap_buffers(&damage);
+				pending_commit = Some(serial);
+				needs_commit = false;
+			}
+		}
+
 		let mut full_update = false;
 		select! {
 			res = socket.next() => {
 				let Some(res) = res else { break; };
 				let event: Event = res.unwrap();
 				match event {
+					Event::Ack { serial, result } => {
+						if pending_commit == Some(serial) {
+							pending_commit = None;
+						}
+						if let Some(format) = pending_loads.remove(&serial) {
+							// An empty or unavailable selection pastes nothing.
+							if let Ok(Reply::SelectionData(data)) = result {
+								let text = format(&String::from_utf8_lossy(&data));
+								pty_channel.send(alacritty_terminal::event_loop::Msg::Input(text.into_bytes().into())).unwrap();
+							}
+							continue;
+						}
+						if serial == create_serial {
+							let Reply::SurfaceCreated(id) = result.expect("WM rejected surface creation") else {
+								panic!("unexpected reply to surface creation");
+							};
+							surface_id = Some(id);
+							socket.write(&Request { serial: serials.next().unwrap(), command: Command::SetAppId { surface: id, app_id: Some("rmox-term".into()) } }).await.unwrap();
+							if title.is_some() {
+								socket.write(&Request { serial: serials.next().unwrap(), command: Command::SetTitle { surface: id, title: title.clone() } }).await.unwrap();
+							}
+							continue;
+						}
+						// The WM rejects other commands in normal cases, e.g., a `SendSelection` for a paste whose requester has disconnected.
+						if let Err(error) = result {
+							tracing::warn!(?serial, ?error, "WM rejected command");
+						}
+						continue;
+					}
+					Event::FocusedSurface(..) => continue,
+					Event::SelectionRequest { transfer, selection, mime_type: _ } => {
+						let data = selections.get(&selection).map(|text| text.clone().into_bytes());
+						socket.write(&Request { serial: serials.next().unwrap(), command: Command::SendSelection { transfer, data } }).await.unwrap();
+						continue;
+					}
+					Event::SelectionCancelled { selection } => {
+						selections.remove(&selection);
+						continue;
+					}
 					Event::Surface { id: _, event } => match event {
+						SurfaceEvent::Buffers { size, generation } => {
+							let fd = socket.take_fd().expect("WM did not send the surface buffers");
+							let buffers = SurfaceBuffers::open(fd, size).unwrap();
+							fb = Some(Framebuffer::from_surface_buffers(buffers));
+							buffers_generation = generation;
+							continue;
+						}
 						SurfaceEvent::Description(new_desc) => {
 							desc = Some(new_desc);
 							dimensions = desc_to_dimensions(&new_desc);
@@ -183,6 +293,7 @@ async fn main() {
 							InputEvent::Text(text) => pty_channel.send(alacritty_terminal::event_loop::Msg::Input(String::from(text).into_bytes().into())).unwrap(),
 							_ => continue,
 						},
+						SurfaceEvent::FocusIn | SurfaceEvent::FocusOut => continue,
 					},
 				}
 			}
@@ -191,10 +302,28 @@ async fn main() {
 				match event {
 					// TODO: Anything else we need to do here?
 					E::MouseCursorDirty => {}
-					// TODO: Title support in the WM.
-					E::Title(..) | E::ResetTitle => continue,
-					// TODO: Clipboard support in the WM.
-					E::ClipboardStore(..) | E::ClipboardLoad(..) => continue,
+					E::Title(..) | E::ResetTitle => {
+						title = match event {
+							E::Title(new_title) => Some(new_title.into()),
+							_ => None,
+						};
+						if let Some(surface) = surface_id {
+							socket.write(&Request { serial: serials.next().unwrap(), command: Command::SetTitle { surface, title: title.clone() } }).await.unwrap();
+						}
+						continue;
+					}
+					E::ClipboardStore(ty, text) => {
+						let selection = clipboard_type_to_selection(ty);
+						selections.insert(selection, text);
+						socket.write(&Request { serial: serials.next().unwrap(), command: Command::OfferSelection { selection, mime_types: vec![TEXT_MIME_TYPE.into()] } }).await.unwrap();
+						continue;
+					}
+					E::ClipboardLoad(ty, format) => {
+						let serial = serials.next().unwrap();
+						pending_loads.insert(serial, format);
+						socket.write(&Request { serial, command: Command::RequestSelection { selection: clipboard_type_to_selection(ty), mime_type: TEXT_MIME_TYPE.into() } }).await.unwrap();
+						continue;
+					}
 					// TODO: Change if/when implementing colors.
 					E::ColorRequest(_index, format) => {
 						let color = format(alacritty_terminal::vte::ansi::Rgb { r: 0, g: 0, b: 0 });
@@ -214,17 +343,20 @@ async fn main() {
 			_ = &mut pty_debounce, if !pty_debounce.is_elapsed() => { /* fall through to drawing code */ }
 		}
 
-		let Some(desc) = desc else {
+		let (Some(desc), Some(surface_fb)) = (desc, &mut fb) else {
 			continue;
 		};
+		let desc = desc.buffer_description();
 
-		let mut fb = desc.transform(&mut fb);
+		// Each damaged line and cursor cell is updated separately, so merge them before submitting.
+		let mut fb = Coalescing::new(Grayscale::new(desc.transform(surface_fb)));
 
 		let point_to_pos = |point: alacritty_terminal::index::Point| {
 			let point = vec2(point.column.0.try_into().unwrap(), point.line.0);
 			(point * cell_size).to_pos()
 		};
-		let draw_cell = |fb: &mut Transformed<Framebuffer>, cell: Indexed<&Cell>| {
+		let draw_cell = |fb: &mut Coalescing<Grayscale<Transformed<Framebuffer>>>,
+		                 cell: Indexed<&Cell>| {
 			let pos = point_to_pos(cell.point);
 
 			let mut str_buf = [0u8; 4];
@@ -252,7 +384,8 @@ async fn main() {
 			let cursor_pos = point_to_pos(cursor.point);
 			Rectangle::new(cursor_pos, cell_size)
 		};
-		let draw_cursor = |fb: &mut Transformed<Framebuffer>, cursor: &RenderableCursor| {
+		let draw_cursor = |fb: &mut Coalescing<Grayscale<Transformed<Framebuffer>>>,
+		                   cursor: &RenderableCursor| {
 			let cursor_rect = cursor_rect(cursor);
 			// Just using the underline cursor for now since it refreshes better.
 			/*
@@ -355,5 +488,9 @@ async fn main() {
 		}
 		terminal.reset_damage();
 		drop(terminal);
+		fb.commit_async().await.unwrap();
+		drop(fb);
+
+		needs_commit = true;
 	}
 }
@@ -1,11 +1,15 @@
 use embedded_graphics::draw_target::DrawTarget;
 use embedded_graphics::geometry::Dimensions;
-use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::pixelcolor::{Gray8, GrayColor as _};
 use embedded_graphics::primitives::PointsIter as _;
-use rmox_common::eink_update::{EinkUpdateExt as _, UpdateStyle};
-use rmox_fb::Framebuffer;
+use rmox_common::eink_update::{EinkUpdateAsyncExt as _, UpdateDepth, UpdateStyle};
+use rmox_fb::util::Grayscale;
+use rmox_fb::{Framebuffer, SurfaceBuffers};
 use rmox_protocol::client::recv::{Event, SurfaceEvent};
-use rmox_protocol::client::send::{Command, SurfaceInit};
+use rmox_protocol::client::send::{Command, Request, SurfaceInit};
+use rmox_protocol::handshake::Capabilities;
+use rmox_protocol::io::UnixTransport;
+use rmox_protocol::Serial;
 use tokio::pin;
 use tokio_stream::StreamExt as _;
 
@@ -19,42 +23,123 @@ async fn main() {
 	let socket = tokio::net::UnixStream::connect(&socket_path)
 		.await
 		.unwrap_or_else(|error| panic!("connecting to {socket_path:?} (RMOX_SOCKET): {error}"));
-	let socket = rmox_protocol::io::Stream::new(socket);
+	let socket = UnixTransport::new(socket);
+	let (socket, _) = rmox_protocol::handshake::client(socket, Capabilities::empty())
+		.await
+		.unwrap_or_else(|error| panic!("handshake with WM: {error}"));
 	pin!(socket);
 
+	let mut serials = (0..).map(Serial);
+	let create_serial = serials.next().unwrap();
+
 	socket
-		.write(&Command::CreateSurface(SurfaceInit::Wallpaper))
+		.write(&Request {
+			serial: create_serial,
+			command: Command::CreateSurface(SurfaceInit::Wallpaper),
+		})
 		.await
 		.unwrap();
 
-	let mut fb = Framebuffer::open().unwrap();
+	let mut fb: Option<Framebuffer> = None;
+	let mut buffers_generation = 0;
+	// The serial of the commit that the WM has not acknowledged yet, and whether more was drawn since.
+	let mut pending_commit = None;
+	let mut needs_commit = false;
+	let mut surface = None;
+
+	loop {
+		// The WM may still be reading the last committed buffer, which flipping after another commit would overwrite.
+		if needs_commit && pending_commit.is_none() {
+			if let (Some(surface_fb), Some(surface)) = (&mut fb, surface) {
+				// The updates were only recorded, so they tell the WM what to refresh.
+				let damage: Vec<_> = surface_fb
+					.take_update_log()
+					.unwrap()
+					.into_iter()
+					.map(|update| update.area)
+					.collect();
+				let serial = serials.next().unwrap();
+				socket
+					.write(&Request {
+						serial,
+						command: Command::Commit {
+							surface,
+							generation: buffers_generation,
+							damage: damage.clone(),
+							style: UpdateStyle::Monochrome,
+							depth: UpdateDepth::Partial,
+						},
+					})
+					.await
+					.unwrap();
+				surface_fb.swap_buffers(&damage);
+				pending_commit = Some(serial);
+				needs_commit = false;
+			}
+		}
 
-	while let Some(res) = socket.next().await {
+		let Some(res) = socket.next().await else {
+			break;
+		};
 		let event = res.unwrap();
 		let desc = match event {
-			Event::Surface { id: _, event } => match event {
-				SurfaceEvent::Description(desc) => desc,
+			Event::Ack { serial, result } => {
+				if pending_commit == Some(serial) {
+					pending_commit = None;
+				}
+				if serial == create_serial {
+					result.expect("WM rejected surface creation");
+				} else if let Err(error) = result {
+					// The WM rejects other commands in normal cases, e.g., a `Commit` for a surface that it removed while the commit was in flight.
+					tracing::warn!(?serial, ?error, "WM rejected command");
+				}
+				continue;
+			}
+			Event::FocusedSurface(..)
+			| Event::SelectionRequest { .. }
+			| Event::SelectionCancelled { .. } => continue,
+			Event::Surface { id, event } => match event {
+				SurfaceEvent::Buffers { size, generation } => {
+					let fd = socket
+						.take_fd()
+						.expect("WM did not send the surface buffers");
+					let buffers = SurfaceBuffers::open(fd, size).unwrap();
+					fb = Some(Framebuffer::from_surface_buffers(buffers));
+					buffers_generation = generation;
+					continue;
+				}
+				SurfaceEvent::Description(desc) => {
+					surface = Some(id);
+					desc
+				}
 				SurfaceEvent::Quit => break,
-				SurfaceEvent::Input(..) => continue,
+				SurfaceEvent::Input(..) | SurfaceEvent::FocusIn | SurfaceEvent::FocusOut => continue,
 			},
 		};
 
+		let Some(surface_fb) = &mut fb else {
+			continue;
+		};
 		if !desc.visible {
 			continue;
 		}
 
-		let mut fb = desc.transform(&mut fb);
+		let desc = desc.buffer_description();
+		let mut fb = Grayscale::new(desc.transform(surface_fb));
 
-		fb.clear(Rgb565::new(31, 63, 31)).unwrap();
+		fb.clear(Gray8::WHITE).unwrap();
 		fb.draw_iter(
 			fb.bounding_box()
 				.points()
 				.filter(|point| (point.x / 2) % 3 == 0 && (point.y / 2) % 3 == 0)
-				.map(|point| embedded_graphics::Pixel(point, Rgb565::new(0, 0, 0))),
+				.map(|point| embedded_graphics::Pixel(point, Gray8::BLACK)),
 		)
 		.unwrap();
 
-		fb.update_partial(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+		fb.update_partial_async(&fb.bounding_box().into(), UpdateStyle::Monochrome)
+			.await
 			.unwrap();
+
+		needs_commit = true;
 	}
 }
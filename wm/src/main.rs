@@ -1,34 +1,133 @@
 use std::collections::HashMap;
 use std::ffi::OsString;
+use std::os::fd::{AsFd as _, OwnedFd};
 use std::path::PathBuf;
-use std::time::Duration;
 
 use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::pixelcolor::Rgb565;
-use rmox_common::eink_update::{EinkUpdateExt as _, UpdateStyle};
-use rmox_common::types::{Pos2, Rectangle, Rotation, Side};
-use rmox_fb::Framebuffer;
+use embedded_graphics::geometry::Point;
+use embedded_graphics::mono_font::{ascii as fonts, MonoFont, MonoTextStyle};
+use embedded_graphics::pixelcolor::{Rgb565, RgbColor as _};
+use embedded_graphics::primitives::{Primitive as _, PrimitiveStyle};
+use embedded_graphics::text::{Baseline, Text};
+use embedded_graphics::Drawable as _;
+use rmox_common::eink_update::{
+	EinkUpdateAsync as _, EinkUpdateAsyncExt as _, UpdateDepth, UpdateOptions, UpdateStyle,
+};
+use rmox_common::types::{rect, Pos2, Rectangle, Rotation, Side, Vec2};
+use rmox_fb::{Framebuffer, FramebufferConfig, SurfaceBuffers};
 use rmox_input::keyboard::{Key, KeyEvent};
 use rmox_input::Input;
-use rmox_protocol::server::recv::{Command, SurfaceInit};
-use rmox_protocol::server::send::{Event, InputEvent, SurfaceDescription, SurfaceEvent};
-use rmox_protocol::server_to_client::{StylusEvent, StylusPhase, TouchEvent, TouchPhase};
-use rmox_protocol::{Id, SurfaceId, TaskId};
+use rmox_protocol::handshake::Capabilities;
+use rmox_protocol::io::UnixTransport;
+use rmox_protocol::server::recv::{Command, Request, SurfaceInit};
+use rmox_protocol::server::send::{
+	CommandError, Event, InputEvent, Reply, SurfaceDescription, SurfaceEvent, SurfaceInfo,
+};
+use rmox_protocol::server_to_client::{
+	StylusEvent, StylusPhase, StylusState, TouchEvent, TouchPhase, TouchState,
+};
+use rmox_protocol::{Id, Selection, Serial, SurfaceId, TaskId, TransferId};
 use tokio::sync::mpsc;
 use tokio::{pin, select};
 use tokio_stream::StreamExt as _;
 
 // TODO: Make all child surfaces invisible and pause input on SIGSTOP (and resume on SIGCONT) so children stop rendering or doing anything.
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 struct Surface {
 	description: SurfaceDescription,
 	task: TaskId,
+	info: SurfaceInfo,
+	/// Shared with the client, and `None` until the surface has an area.
+	buffers: Option<SurfaceBuffers>,
+	/// Incremented whenever `buffers` are replaced, so that commits for older buffers can be rejected.
+	buffers_generation: u32,
+	/// The event that sends `buffers` to the client, until it is sent along with the next description.
+	unsent_buffers: Option<Outgoing>,
+}
+
+impl Surface {
+	fn new(task: TaskId, rotation: Rotation) -> Self {
+		Self {
+			description: SurfaceDescription {
+				// Will be set by `reassign_areas`.
+				base_rect: Rectangle::ZERO,
+				rotation,
+				scale: 1,
+				visible: true,
+			},
+			task,
+			info: SurfaceInfo::default(),
+			buffers: None,
+			buffers_generation: 0,
+			unsent_buffers: None,
+		}
+	}
+
+	/// Replace the buffers if the surface's size has changed.
+	/// The client is sent the new ones along with its next description.
+	fn resize_buffers(&mut self, id: SurfaceId) {
+		let size = self.description.base_rect.size;
+		if self.buffers.as_ref().map(SurfaceBuffers::size) == Some(size) {
+			return;
+		}
+		self.buffers = None;
+		self.buffers_generation = self.buffers_generation.wrapping_add(1);
+		self.unsent_buffers = None;
+		if size.x <= 0 || size.y <= 0 {
+			return;
+		}
+
+		let Ok((buffers, fd)) = SurfaceBuffers::create(size)
+			.inspect_err(|error| tracing::error!(?id, ?error, "creating surface buffers"))
+		else {
+			return;
+		};
+		self.buffers = Some(buffers);
+		self.unsent_buffers = Some(Outgoing {
+			event: Event::Surface {
+				id,
+				event: SurfaceEvent::Buffers {
+					size,
+					generation: self.buffers_generation,
+				},
+			},
+			fd: Some(fd),
+		});
+	}
+}
+
+/// An event for a client, along with a file descriptor to send with it.
+#[derive(Debug)]
+struct Outgoing {
+	event: Event,
+	fd: Option<OwnedFd>,
+}
+
+impl From<Event> for Outgoing {
+	fn from(event: Event) -> Self {
+		Self { event, fd: None }
+	}
+}
+
+#[derive(Debug)]
+struct SelectionOwner {
+	task: TaskId,
+	mime_types: Vec<Box<str>>,
+}
+
+/// A request for selection data that is waiting for the owner's answer.
+#[derive(Debug)]
+struct Transfer {
+	owner: TaskId,
+	requester: TaskId,
+	/// The serial of the requester's `RequestSelection` command.
+	serial: Serial,
 }
 
 #[derive(Debug)]
 struct Task {
-	channel: mpsc::Sender<Event>,
+	channel: mpsc::Sender<Outgoing>,
 }
 
 #[derive(Debug)]
@@ -42,18 +141,323 @@ struct ShellLayer {
 enum ContainerKind {
 	Horizontal,
 	Vertical,
+	/// Only the focused child is visible, below a row of tabs.
+	Tabbed,
+	/// Only the focused child is visible, below a column of title bars.
+	Stacked,
+}
+
+impl ContainerKind {
+	/// Whether all children are visible, each with a share of the container.
+	fn is_split(&self) -> bool {
+		matches!(self, Self::Horizontal | Self::Vertical)
+	}
+
+	/// The side that the first child is placed at, before the global rotation is applied.
+	///
+	/// For tabbed and stacked containers, this is where the first tab is.
+	fn start_side(&self) -> Side {
+		match self {
+			Self::Horizontal | Self::Tabbed => Side::Left,
+			Self::Vertical | Self::Stacked => Side::Top,
+		}
+	}
+}
+
+/// The weight of the first child of a container.
+/// Later children start with the average weight of their siblings, so that they get an equal share.
+const DEFAULT_WEIGHT: u32 = 1024;
+
+/// Resizing will not make a child smaller than this along its container.
+const MIN_CHILD_SIZE: i32 = 64;
+
+/// How close a touch or the stylus must start to the border between two children to drag it.
+///
+/// Input that grabs a border is not sent to any surface, so this is kept small to avoid eating strokes near the edges of surfaces.
+const BORDER_GRAB_DISTANCE: i32 = 4;
+
+/// The height of each tab, or of each title bar in stacked containers.
+const TAB_HEIGHT: i32 = 32;
+const TAB_FONT: &MonoFont<'static> = &fonts::FONT_10X20;
+const TAB_PADDING: i32 = 6;
+
+/// The size of `rect` along containers whose children start from `side`.
+fn length_along(side: Side, rect: &Rectangle) -> i32 {
+	match side {
+		Side::Top | Side::Bottom => rect.size.y,
+		Side::Left | Side::Right => rect.size.x,
+	}
+}
+
+/// How far `point` is from the edge of `rect` at `side`, toward the opposite side.
+fn distance_along(side: Side, rect: &Rectangle, point: Pos2) -> i32 {
+	match side {
+		Side::Top => point.y - rect.origin.y,
+		Side::Right => rect.end().x - point.x,
+		Side::Bottom => rect.end().y - point.y,
+		Side::Left => point.x - rect.origin.x,
+	}
+}
+
+#[derive(Debug)]
+struct Child {
+	node: ShellNode,
+	/// The share of the container's size that the child gets, relative to the weights of its siblings.
+	weight: u32,
 }
 
 #[derive(Debug)]
 struct Container {
 	rect: Rectangle,
 	kind: ContainerKind,
-	children: Vec<ShellNode>,
+	children: Vec<Child>,
+	/// The index of the child that was most recently focused, which is used when focus moves down into this container.
+	/// May be out of bounds if children have been removed since.
+	focused: u8,
 }
 
 impl Container {
+	fn new(kind: ContainerKind, child: ShellNode) -> Self {
+		Self {
+			// Will be set by `reassign_areas`.
+			rect: Rectangle::ZERO,
+			kind,
+			children: vec![Child {
+				node: child,
+				weight: DEFAULT_WEIGHT,
+			}],
+			focused: 0,
+		}
+	}
+
+	/// An empty container to temporarily take the place of another node, which `retain` removes.
+	fn placeholder() -> Self {
+		Self {
+			rect: Rectangle::ZERO,
+			kind: ContainerKind::Horizontal,
+			children: Vec::new(),
+			focused: 0,
+		}
+	}
+
+	/// Insert `node` at `index`, or at the end if `index` is out of bounds, with an equal share of the container.
+	fn insert(&mut self, index: usize, node: ShellNode) {
+		let total: u64 = self
+			.children
+			.iter()
+			.map(|child| u64::from(child.weight))
+			.sum();
+		let weight = u64::try_from(self.children.len())
+			.ok()
+			.and_then(|len| total.checked_div(len))
+			.map_or(DEFAULT_WEIGHT, |weight| {
+				u32::try_from(weight).unwrap().max(1)
+			});
+		let index = index.min(self.children.len());
+		self.children.insert(index, Child { node, weight });
+	}
+
+	/// Divide the container's rect between the children according to their weights, starting from `side`.
+	fn child_rects(&self, side: Side) -> Vec<Rectangle> {
+		let size = i64::from(length_along(side, &self.rect));
+		let total: i64 = self
+			.children
+			.iter()
+			.map(|child| i64::from(child.weight))
+			.sum();
+
+		let mut rect = self.rect;
+		let mut weight = 0;
+		let mut taken = 0;
+		let mut rects = Vec::with_capacity(self.children.len());
+		for child in &self.children[..self.children.len() - 1] {
+			weight += i64::from(child.weight);
+			// Rounding the ends rather than the sizes of the children avoids accumulating errors.
+			let end = size * weight / total;
+			rects.push(side.take((end - taken).try_into().unwrap(), &mut rect));
+			taken = end;
+		}
+		// The last child absorbs any remainder.
+		rects.push(rect);
+		rects
+	}
+
+	/// Scale the weight of the child at `index` by `numerator / denominator`, relative to its siblings.
+	///
+	/// Returns `false` without changing anything if that would make any child smaller than `MIN_CHILD_SIZE`,
+	/// unless it was already smaller.
+	fn scale_weight(&mut self, index: u8, numerator: u32, denominator: u32, side: Side) -> bool {
+		let old_rects = self.child_rects(side);
+		let child = &mut self.children[usize::from(index)];
+		let old_weight = child.weight;
+		child.weight =
+			u32::try_from(u64::from(old_weight) * u64::from(numerator) / u64::from(denominator))
+				.unwrap_or(u32::MAX)
+				.max(1);
+
+		let too_small = self
+			.child_rects(side)
+			.iter()
+			.zip(&old_rects)
+			.any(|(new, old)| {
+				let new = length_along(side, new);
+				new < MIN_CHILD_SIZE && new < length_along(side, old)
+			});
+		if too_small {
+			self.children[usize::from(index)].weight = old_weight;
+		}
+		!too_small
+	}
+
+	/// Move the border between the children at `index` and `index + 1` to `position`,
+	/// keeping both of them at least `MIN_CHILD_SIZE` and the other children at their current sizes.
+	fn drag_border(&mut self, index: u8, position: Pos2, side: Side) {
+		let index = usize::from(index);
+		let rects = self.child_rects(side);
+		let (Some(first), Some(second)) = (rects.get(index), rects.get(index + 1)) else {
+			return;
+		};
+		let total = length_along(side, first) + length_along(side, second);
+		if total < 2 * MIN_CHILD_SIZE {
+			return;
+		}
+		let first_length =
+			distance_along(side, first, position).clamp(MIN_CHILD_SIZE, total - MIN_CHILD_SIZE);
+
+		// Using the sizes as weights keeps the other children exactly as they are.
+		for (child, rect) in self.children.iter_mut().zip(&rects) {
+			child.weight = u32::try_from(length_along(side, rect)).unwrap_or(0).max(1);
+		}
+		self.children[index].weight = first_length.try_into().unwrap();
+		self.children[index + 1].weight = (total - first_length).try_into().unwrap();
+	}
+
+	/// Find the border between two children that `point` is on, preferring borders in nested containers.
+	///
+	/// `path` should be the path of this container, and is left as the path of the container with the border.
+	/// The returned index is that of the child before the border.
+	fn border_at(&self, point: Pos2, rotation: Rotation, path: &mut Path) -> Option<u8> {
+		if !self.rect.contains(point) {
+			return None;
+		}
+
+		for (i, child) in self.visible_children() {
+			if let ShellNode::Container(container) = &child.node {
+				path.push(i.try_into().unwrap());
+				if let Some(index) = container.border_at(point, rotation, path) {
+					return Some(index);
+				}
+				path.pop();
+			}
+		}
+		if !self.kind.is_split() {
+			return None;
+		}
+
+		let side = self.kind.start_side().rotate(rotation);
+		let rects = self.child_rects(side);
+		rects[..rects.len() - 1]
+			.iter()
+			.position(|rect| {
+				(distance_along(side, rect, point) - length_along(side, rect)).abs() <= BORDER_GRAB_DISTANCE
+			})
+			.map(|index| index.try_into().unwrap())
+	}
+
+	fn focused_child(&self) -> u8 {
+		// We assert that containers have at least one item.
+		self
+			.focused
+			.min((self.children.len() - 1).try_into().unwrap())
+	}
+
+	/// The children that are visible if the container is, along with their indices.
+	fn visible_children(&self) -> impl Iterator<Item = (usize, &Child)> {
+		let focused = usize::from(self.focused_child());
+		let split = self.kind.is_split();
+		self
+			.children
+			.iter()
+			.enumerate()
+			.filter(move |(i, _)| split || *i == focused)
+	}
+
+	/// For tabbed and stacked containers, split the container's rect into the tab strip at the top and the area of the visible child.
+	fn split_tab_strip(&self, rotation: Rotation) -> (Rectangle, Rectangle) {
+		let rows = match self.kind {
+			ContainerKind::Stacked => i32::try_from(self.children.len()).unwrap(),
+			_ => 1,
+		};
+		let side = Side::Top.rotate(rotation);
+		let mut rect = self.rect;
+		let strip = side.take(
+			(TAB_HEIGHT * rows).min(length_along(side, &rect)),
+			&mut rect,
+		);
+		(strip, rect)
+	}
+
+	fn tab_strips(
+		&self,
+		rotation: Rotation,
+		title: &impl Fn(SurfaceId) -> Box<str>,
+		strips: &mut Vec<TabStrip>,
+	) {
+		if !self.kind.is_split() {
+			let titles = self
+				.children
+				.iter()
+				.map(|child| match &child.node {
+					ShellNode::Container(container) => title(container.focused_surface()),
+					ShellNode::Surface(id) => title(*id),
+				})
+				.collect();
+			strips.push(TabStrip {
+				rect: self.split_tab_strip(rotation).0,
+				stacked: matches!(self.kind, ContainerKind::Stacked),
+				titles,
+				focused: self.focused_child().into(),
+			});
+		}
+
+		for (_, child) in self.visible_children() {
+			if let ShellNode::Container(container) = &child.node {
+				container.tab_strips(rotation, title, strips);
+			}
+		}
+	}
+
+	/// Follow the most recently focused children down to a surface.
+	fn focused_surface(&self) -> SurfaceId {
+		match &self.children[usize::from(self.focused_child())].node {
+			ShellNode::Container(container) => container.focused_surface(),
+			ShellNode::Surface(id) => *id,
+		}
+	}
+
+	/// Add the surfaces in this container and its descendants to `surfaces`.
+	fn surfaces(&self, surfaces: &mut Vec<SurfaceId>) {
+		for child in &self.children {
+			match &child.node {
+				ShellNode::Container(container) => container.surfaces(surfaces),
+				ShellNode::Surface(id) => surfaces.push(*id),
+			}
+		}
+	}
+
+	/// Remember the children along `path` as the most recently focused ones.
+	fn record_focus(&mut self, path: &[u8]) {
+		let [index, rest @ ..] = path else {
+			return;
+		};
+		self.focused = *index;
+		if let ShellNode::Container(container) = &mut self.children[usize::from(*index)].node {
+			container.record_focus(rest);
+		}
+	}
+
 	fn retain(&mut self, f: &mut impl FnMut(SurfaceId) -> bool) -> bool {
-		self.children.retain_mut(|child| child.retain(f));
+		self.children.retain_mut(|child| child.node.retain(f));
 		!self.children.is_empty()
 	}
 
@@ -61,8 +465,7 @@ impl Container {
 		let container_index = if let Some(&index) = path.get(i) {
 			index
 		} else {
-			// Arbitrary choice.
-			let index = 0;
+			let index = self.focused_child();
 			path.push(index);
 			index
 		};
@@ -73,7 +476,7 @@ impl Container {
 			// We assert that containers have at least one item.
 			self.children.last().unwrap()
 		};
-		child.fix_path(path, i + 1);
+		child.node.fix_path(path, i + 1);
 	}
 
 	fn get_path(&self, path: &[u8]) -> Option<&ShellNode> {
@@ -82,10 +485,22 @@ impl Container {
 			return None;
 		};
 
-		match &self.children[usize::from(*index)] {
-			ShellNode::Container(container) => container.get_path(rest),
+		match &self.children[usize::from(*index)].node {
+			ShellNode::Container(container) if !rest.is_empty() => container.get_path(rest),
 			// If `None`, path is too deep.
-			node @ ShellNode::Surface(_) => rest.is_empty().then_some(node),
+			node => rest.is_empty().then_some(node),
+		}
+	}
+
+	fn get_container(&self, path: &[u8]) -> Option<&Self> {
+		let [index, rest @ ..] = path else {
+			return Some(self);
+		};
+
+		match &self.children[usize::from(*index)].node {
+			ShellNode::Container(container) => container.get_container(rest),
+			// Path is too deep.
+			ShellNode::Surface(_) => None,
 		}
 	}
 
@@ -94,21 +509,179 @@ impl Container {
 			return Some(self);
 		};
 
-		match &mut self.children[usize::from(*index)] {
+		match &mut self.children[usize::from(*index)].node {
 			ShellNode::Container(container) => container.get_container_mut(rest),
 			// Path is too deep.
 			ShellNode::Surface(_) => None,
 		}
 	}
 
+	/// The path of the container at `path`, or of the parent of the surface at `path`.
+	fn container_path<'a>(&self, path: &'a [u8]) -> &'a [u8] {
+		match self.get_path(path) {
+			Some(ShellNode::Surface(_)) => &path[..path.len() - 1],
+			_ => path,
+		}
+	}
+
+	fn surface_to_path(&self, surface: SurfaceId, path: &mut Path) -> bool {
+		for (i, child) in self.children.iter().enumerate() {
+			path.push(i.try_into().unwrap());
+			let found = match &child.node {
+				ShellNode::Container(container) => container.surface_to_path(surface, path),
+				ShellNode::Surface(id) => *id == surface,
+			};
+			if found {
+				return true;
+			}
+			path.pop();
+		}
+
+		false
+	}
+
+	/// Move the node at `from` into the container at `to`, at `index`, where `self` is the root.
+	///
+	/// Containers that are left empty are removed.
+	/// Returns the new path of the node, or `None` if `from` is the root or doesn't exist.
+	fn move_node(&mut self, from: &[u8], to: &[u8], index: u8) -> Option<Path> {
+		debug_assert!(!to.starts_with(from), "cannot move a node into itself");
+		// The root container cannot be moved.
+		let (&from_index, from_parent) = from.split_last()?;
+		// Paths change as nodes are moved, so we find the node afterwards using one of its surfaces.
+		let surface = match self.get_path(from)? {
+			ShellNode::Surface(id) => *id,
+			ShellNode::Container(container) => container.focused_surface(),
+		};
+		let mut path = Vec::new();
+		self.surface_to_path(surface, &mut path);
+		let depth = path.len() - from.len();
+
+		// Leave an empty container in place of the node so that `to` remains valid.
+		let node = std::mem::replace(
+			&mut self.get_container_mut(from_parent).unwrap().children[usize::from(from_index)].node,
+			ShellNode::Container(Container::placeholder()),
+		);
+		self
+			.get_container_mut(to)
+			.unwrap()
+			.insert(index.into(), node);
+		// Removes the placeholder, along with any containers that it was the only child of.
+		self.retain(&mut |_| true);
+
+		let mut path = Vec::new();
+		self.surface_to_path(surface, &mut path);
+		path.truncate(path.len() - depth);
+		Some(path)
+	}
+
+	/// Move the node at `path` toward `direction`, like in i3, where `self` is the root.
+	///
+	/// Within a container along `direction`, the node is swapped with its neighbor, or moved into it if it is a container.
+	/// Otherwise, the node is moved out into the nearest ancestor along `direction`, next to the child that it was in.
+	/// Returns the new path of the node, or `None` if it can't move any further.
+	fn move_toward(&mut self, path: &[u8], direction: Side) -> Option<Path> {
+		let mut path = path.to_owned();
+		// The root container cannot be moved.
+		let (&index, parent_path) = path.split_last()?;
+		// Whether `direction` is toward the last child of a container of `kind`, or `None` if the container is not along `direction`.
+		let toward_end = |kind: &ContainerKind| {
+			let start = kind.start_side();
+			if direction == start {
+				Some(false)
+			} else if direction == start.rotate(Rotation::Rotate180) {
+				Some(true)
+			} else {
+				None
+			}
+		};
+
+		let parent = self.get_container_mut(parent_path).unwrap();
+		if let Some(toward_end) = toward_end(&parent.kind) {
+			let neighbor = if toward_end {
+				index.checked_add(1)
+			} else {
+				index.checked_sub(1)
+			};
+			match neighbor.map(|neighbor| {
+				let child = parent.children.get(usize::from(neighbor));
+				(neighbor, child.map(|child| &child.node))
+			}) {
+				Some((neighbor, Some(ShellNode::Container(container)))) => {
+					let to_index = if toward_end {
+						0
+					} else {
+						container.children.len().try_into().unwrap()
+					};
+					let mut to = parent_path.to_owned();
+					to.push(neighbor);
+					return self.move_node(&path, &to, to_index);
+				}
+				Some((neighbor, Some(ShellNode::Surface(_)))) => {
+					parent
+						.children
+						.swap(usize::from(index), usize::from(neighbor));
+					*path.last_mut().unwrap() = neighbor;
+					return Some(path);
+				}
+				// Already at the edge of the container.
+				_ => {}
+			}
+		}
+
+		let ancestor = (0..parent_path.len()).rev().find_map(|depth| {
+			let container = self.get_container(&path[..depth]).unwrap();
+			Some((depth, toward_end(&container.kind)?))
+		});
+		let (to, to_index) = if let Some((depth, toward_end)) = ancestor {
+			(path[..depth].to_owned(), path[depth] + u8::from(toward_end))
+		} else if toward_end(&self.kind).is_some() {
+			// Already at the edge of the screen.
+			return None;
+		} else {
+			// Like i3, nest the root container in a new one along `direction`.
+			let kind = match direction {
+				Side::Left | Side::Right => ContainerKind::Horizontal,
+				Side::Top | Side::Bottom => ContainerKind::Vertical,
+			};
+			let old = std::mem::replace(self, Container::placeholder());
+			*self = Container::new(kind, ShellNode::Container(old));
+			path.insert(0, 0);
+			let toward_end = matches!(direction, Side::Right | Side::Bottom);
+			(Vec::new(), u8::from(toward_end))
+		};
+		self.move_node(&path, &to, to_index)
+	}
+
+	/// Find the next or previous child of the nearest tabbed or stacked container around the node at `path`, where `self` is the root.
+	///
+	/// Wraps around at either end, and returns `None` if there is no such container.
+	fn cycle_tab(&self, path: &[u8], forward: bool) -> Option<Path> {
+		let (depth, len) = (0..path.len()).rev().find_map(|depth| {
+			let container = self.get_container(&path[..depth]).unwrap();
+			(!container.kind.is_split()).then_some((depth, container.children.len()))
+		})?;
+
+		let index = usize::from(path[depth]);
+		let index = if forward {
+			(index + 1) % len
+		} else {
+			(index + len - 1) % len
+		};
+		let mut path = path[..depth].to_owned();
+		path.push(index.try_into().unwrap());
+		Some(path)
+	}
+
 	fn point_to_path(
 		&self,
 		point: Pos2,
 		path: &mut Vec<u8>,
 		surface_rect: impl Fn(SurfaceId) -> Rectangle,
 	) -> bool {
-		for (i, child) in self.children.iter().enumerate() {
-			match child {
+		// Hidden children of tabbed and stacked containers overlap the visible one.
+		for (i, child) in self.visible_children() {
+			match &child.node {
 				ShellNode::Container(container) => {
 					if container.rect.contains(point) {
 						path.push(i.try_into().unwrap());
@@ -146,7 +719,7 @@ impl ShellNode {
 	fn fix_path(&self, path: &mut Path, i: usize) {
 		match self {
 			Self::Surface(_) => {
-				path.truncate(i + 1);
+				path.truncate(i);
 			}
 			Self::Container(container) => container.fix_path(path, i),
 		}
@@ -171,11 +744,55 @@ impl Shell {
 		self.wallpaper = self.wallpaper.filter(|id| f(*id));
 	}
 
+	/// Like [`Self::retain`], but `focused` is updated to keep pointing at the same node,
+	/// since removing other surfaces may shift it within its container.
+	/// Only if the focused node itself was removed is the path fixed to point elsewhere.
+	fn retain_focused(&mut self, f: impl FnMut(SurfaceId) -> bool, focused: &mut Option<Path>) {
+		// The focused node is found again afterwards through any of its surfaces that remain.
+		let mut surfaces = Vec::new();
+		if let Some(path) = focused.as_deref() {
+			if let Some(container) = self.get_container(path) {
+				container.surfaces(&mut surfaces);
+			} else if let Some(ShellNode::Surface(id)) = self.get_path(path) {
+				surfaces.push(*id);
+			}
+		}
+		self.retain(f);
+
+		// Containers are only removed once they are empty, so the ancestors of a remaining surface keep their depth.
+		let depth = focused.as_ref().map_or(0, Vec::len);
+		if let Some(mut path) = surfaces
+			.into_iter()
+			.find_map(|surface| self.surface_to_path(surface))
+		{
+			path.truncate(depth);
+			*focused = Some(path);
+		} else {
+			self.fix_path(focused);
+		}
+	}
+
 	fn get_path(&self, path: &[u8]) -> Option<&ShellNode> {
 		let root = self.root.as_ref()?;
 		root.get_path(path)
 	}
 
+	fn get_container(&self, path: &[u8]) -> Option<&Container> {
+		let root = self.root.as_ref()?;
+		root.get_container(path)
+	}
+
+	fn border_at(&self, point: Pos2, rotation: Rotation) -> Option<BorderGrab> {
+		let root = self.root.as_ref()?;
+		let mut path = Vec::new();
+		let index = root.border_at(point, rotation, &mut path)?;
+		Some(BorderGrab {
+			container: path,
+			index,
+			position: point,
+		})
+	}
+
 	fn fix_path(&mut self, path: &mut Option<Path>) {
 		if let Some(root) = &mut self.root {
 			if let Some(path) = path {
@@ -186,6 +803,12 @@ impl Shell {
 		}
 	}
 
+	fn surface_to_path(&self, surface: SurfaceId) -> Option<Path> {
+		let root = self.root.as_ref()?;
+		let mut path = Vec::new();
+		root.surface_to_path(surface, &mut path).then_some(path)
+	}
+
 	fn point_to_path(
 		&self,
 		point: Pos2,
@@ -197,18 +820,113 @@ impl Shell {
 		(root.rect.contains(point) && root.point_to_path(point, &mut path, surface_rect))
 			.then_some(path)
 	}
+
+	/// Find the visible surface containing `point`, checking layers, then tiled surfaces, then the wallpaper.
+	fn surface_at(
+		&self,
+		point: Pos2,
+		surface_rect: impl Fn(SurfaceId) -> Rectangle,
+	) -> Option<SurfaceId> {
+		if let Some(layer) = self
+			.layers
+			.iter()
+			.find(|layer| surface_rect(layer.surface).contains(point))
+		{
+			return Some(layer.surface);
+		}
+
+		if let Some(path) = self.point_to_path(point, &surface_rect) {
+			if let Some(ShellNode::Surface(id)) = self.get_path(&path) {
+				return Some(*id);
+			}
+		}
+
+		// The wallpaper is only visible if there are no tiled surfaces.
+		self
+			.wallpaper
+			.filter(|&id| self.root.is_none() && surface_rect(id).contains(point))
+	}
 }
 
 #[derive(Debug)]
 struct ManagerConfig {
+	screen_size: Vec2,
 	global_rotation: Rotation,
 	inset: i32,
 	control_socket: OsString,
 }
 
 /// The `Vec` represents a path into `shell.root` where each item is an index into the children of a container, e.g., `Some(vec![1])` is the second child of the root container.
+/// A path may end at a container, e.g., `Some(vec![])` is the root container itself.
 type Path = Vec<u8>;
 
+/// The tabs of a visible tabbed or stacked container, as drawn by the WM.
+#[derive(Debug, PartialEq, Eq)]
+struct TabStrip {
+	rect: Rectangle,
+	/// Whether the tabs are stacked vertically rather than placed side by side.
+	stacked: bool,
+	titles: Vec<Box<str>>,
+	focused: usize,
+}
+
+impl TabStrip {
+	fn draw(&self, fb: &mut Framebuffer, rotation: Rotation) {
+		let description = SurfaceDescription {
+			base_rect: self.rect,
+			rotation,
+			scale: 1,
+			visible: true,
+		};
+		let size = description.size();
+		let mut fb = description.transform(fb);
+
+		let count = i32::try_from(self.titles.len()).unwrap();
+		let char_width = i32::try_from(TAB_FONT.character_size.width).unwrap();
+		for (i, title) in self.titles.iter().enumerate() {
+			let (background, foreground) = if i == self.focused {
+				(Rgb565::BLACK, Rgb565::WHITE)
+			} else {
+				(Rgb565::WHITE, Rgb565::BLACK)
+			};
+			let i = i32::try_from(i).unwrap();
+			let tab = if self.stacked {
+				rect(0, i * TAB_HEIGHT, size.x, TAB_HEIGHT)
+			} else {
+				let x = size.x * i / count;
+				rect(x, 0, size.x * (i + 1) / count - x, size.y)
+			};
+
+			fb.fill_solid(&tab.into(), background).unwrap();
+			embedded_graphics::primitives::Rectangle::from(tab)
+				.into_styled(PrimitiveStyle::with_stroke(Rgb565::BLACK, 1))
+				.draw(&mut fb)
+				.unwrap();
+			// Titles that don't fit are cut off.
+			let max_chars = usize::try_from((tab.size.x - 2 * TAB_PADDING) / char_width).unwrap_or(0);
+			let title: String = title.chars().take(max_chars).collect();
+			Text::with_baseline(
+				&title,
+				Point::new(tab.origin.x + TAB_PADDING, tab.center().y),
+				MonoTextStyle::new(TAB_FONT, foreground),
+				Baseline::Middle,
+			)
+			.draw(&mut fb)
+			.unwrap();
+		}
+	}
+}
+
+/// The border between two children of a container, while it is being dragged.
+#[derive(Debug)]
+struct BorderGrab {
+	container: Path,
+	/// The index of the child before the border.
+	index: u8,
+	/// Where the border has been dragged to so far.
+	position: Pos2,
+}
+
 #[derive(Debug)]
 struct ManagerState {
 	config: ManagerConfig,
@@ -218,9 +936,50 @@ struct ManagerState {
 	surfaces: HashMap<SurfaceId, Surface>,
 	tasks: HashMap<TaskId, Task>,
 	keyboard_focused_container: Option<Path>,
+	/// The surface that was most recently sent `FocusIn`, used to detect focus changes.
+	keyboard_focused_surface: Option<SurfaceId>,
+	/// Each touch is grabbed by the surface it started on until it ends.
+	touch_grabs: HashMap<rmox_input::touch::Id, SurfaceId>,
+	/// The surface that most recently received stylus events.
+	/// While the stylus is touching, this surface has grabbed it.
+	stylus_surface: Option<SurfaceId>,
+	/// Touches that started on a border, which they drag until they end.
+	touch_border_grabs: HashMap<rmox_input::touch::Id, BorderGrab>,
+	/// The border that the stylus touched down on, which it drags until it is lifted.
+	stylus_border_grab: Option<BorderGrab>,
+	/// The tab strips that are currently on the screen.
+	drawn_tab_strips: Vec<TabStrip>,
+	/// The surface that covers the whole screen, hiding all others, until it loses focus.
+	fullscreen_surface: Option<SurfaceId>,
+	/// Whether the margins around the layout were covered by a fullscreen surface and must be cleared.
+	margins_dirty: bool,
+	selections: HashMap<Selection, SelectionOwner>,
+	transfers: HashMap<TransferId, Transfer>,
 }
 
 impl ManagerState {
+	fn new(config: ManagerConfig) -> Self {
+		Self {
+			config,
+
+			id_counter: Id::START,
+
+			surfaces: HashMap::new(),
+			tasks: HashMap::new(),
+			keyboard_focused_container: None,
+			keyboard_focused_surface: None,
+			touch_grabs: HashMap::new(),
+			stylus_surface: None,
+			touch_border_grabs: HashMap::new(),
+			stylus_border_grab: None,
+			drawn_tab_strips: Vec::new(),
+			fullscreen_surface: None,
+			margins_dirty: false,
+			selections: HashMap::new(),
+			transfers: HashMap::new(),
+		}
+	}
+
 	fn next_id(&mut self) -> Id {
 		let ret = self.id_counter;
 		self.id_counter = self.id_counter.step();
@@ -231,82 +990,231 @@ impl ManagerState {
 		&mut self,
 		container: &mut Container,
 		rect: &Rectangle,
+		visible: bool,
 		dirty_surfaces: &mut Vec<SurfaceId>,
 	) {
-		tracing::trace!(?rect, "reassignment - reassign container");
+		tracing::trace!(?rect, ?visible, "reassignment - reassign container");
 		container.rect = *rect;
-		let child_side = match container.kind {
-			ContainerKind::Horizontal => Side::Left,
-			ContainerKind::Vertical => Side::Top,
-		}
-		.rotate(self.config.global_rotation);
-		let child_size = match child_side {
-			Side::Top | Side::Bottom => rect.size.y,
-			Side::Left | Side::Right => rect.size.x,
-		} / i32::try_from(container.children.len()).unwrap();
-		let mut rect = *rect;
-		let len = container.children.len();
-		for child in &mut container.children[..len - 1] {
-			let child_rect = child_side.take(child_size, &mut rect);
-			self.reassign_node(child, &child_rect, dirty_surfaces);
-		}
-		self.reassign_node(
-			container.children.last_mut().unwrap(),
-			&rect,
-			dirty_surfaces,
-		);
+		let child_side = container
+			.kind
+			.start_side()
+			.rotate(self.config.global_rotation);
+		if container.kind.is_split() {
+			let child_rects = container.child_rects(child_side);
+			for (child, child_rect) in container.children.iter_mut().zip(&child_rects) {
+				self.reassign_node(&mut child.node, child_rect, visible, dirty_surfaces);
+			}
+		} else {
+			// Hidden children keep the same area so that switching tabs doesn't resize them.
+			let (_, child_rect) = container.split_tab_strip(self.config.global_rotation);
+			let focused = usize::from(container.focused_child());
+			for (i, child) in container.children.iter_mut().enumerate() {
+				let visible = visible && i == focused;
+				self.reassign_node(&mut child.node, &child_rect, visible, dirty_surfaces);
+			}
+		}
 	}
 
 	fn reassign_node(
 		&mut self,
 		node: &mut ShellNode,
 		rect: &Rectangle,
+		visible: bool,
 		dirty_surfaces: &mut Vec<SurfaceId>,
 	) {
-		tracing::trace!(?rect, "reassignment - reassign node");
+		tracing::trace!(?rect, ?visible, "reassignment - reassign node");
 		match node {
-			ShellNode::Container(container) => self.reassign_container(container, rect, dirty_surfaces),
+			ShellNode::Container(container) => {
+				self.reassign_container(container, rect, visible, dirty_surfaces);
+			}
 			ShellNode::Surface(id) => {
-				let surface = self.surfaces.get_mut(id).unwrap();
-				if surface.description.base_rect != *rect {
-					dirty_surfaces.push(*id);
+				let fullscreen = self.fullscreen_surface;
+				if fullscreen == Some(*id) {
+					let screen_rect = Rectangle::new(Pos2::ZERO, self.config.screen_size);
+					self.assign_surface(*id, screen_rect, true, dirty_surfaces);
+				} else {
+					self.assign_surface(*id, *rect, visible && fullscreen.is_none(), dirty_surfaces);
 				}
-				surface.description.base_rect = *rect;
 			}
 		}
 	}
-}
 
-#[derive(Debug)]
-struct Manager {
-	state: ManagerState,
-	shell: Shell,
-	input: Input,
-}
+	fn prune_shell(&mut self, shell: &mut Shell) {
+		tracing::trace!(?shell, ?self.keyboard_focused_container, "prune shell - before");
+		let surfaces = &self.surfaces;
+		shell.retain_focused(
+			|surface| surfaces.contains_key(&surface),
+			&mut self.keyboard_focused_container,
+		);
+		// Drop any grabs held by surfaces that no longer exist.
+		self
+			.touch_grabs
+			.retain(|_, surface| surfaces.contains_key(surface));
+		self.stylus_surface = self
+			.stylus_surface
+			.filter(|surface| surfaces.contains_key(surface));
+		// The paths of border grabs may no longer be valid.
+		self.touch_border_grabs.clear();
+		self.stylus_border_grab = None;
+		if let Some(fullscreen) = self.fullscreen_surface {
+			if !surfaces.contains_key(&fullscreen) {
+				self.fullscreen_surface = None;
+				self.margins_dirty = true;
+			}
+		}
+		tracing::trace!(?shell, ?self.keyboard_focused_container, "prune shell - after");
+	}
 
-enum ManagerCommand {
-	CreateSurface { task: TaskId, options: SurfaceInit },
-	RemoveTask { task: TaskId },
-}
+	/// Make `surface_id` fullscreen and focus it, or return it to the layout, without reassigning areas.
+	fn set_fullscreen(
+		&mut self,
+		shell: &Shell,
+		surface_id: SurfaceId,
+		fullscreen: bool,
+	) -> Result<(), ()> {
+		let path = shell.surface_to_path(surface_id).ok_or(())?;
+		if fullscreen {
+			self.keyboard_focused_container = Some(path);
+			self.fullscreen_surface = Some(surface_id);
+			self.touch_border_grabs.clear();
+			self.stylus_border_grab = None;
+		} else if self.fullscreen_surface == Some(surface_id) {
+			self.fullscreen_surface = None;
+			self.margins_dirty = true;
+		}
+		Ok(())
+	}
 
-#[derive(Clone)]
-struct ManagerHandle {
-	channel: mpsc::Sender<ManagerCommand>,
-}
+	/// Lay out the whole shell, adding the surfaces whose descriptions changed to `dirty_surfaces`.
+	fn assign_areas(&mut self, shell: &mut Shell, dirty_surfaces: &mut Vec<SurfaceId>) {
+		let fullscreen = self.fullscreen_surface.is_some();
+		let mut rect = Rectangle::new(Pos2::ZERO, self.config.screen_size).inset(self.config.inset);
 
-// TODO: Avoid unwraps when getting tasks and surfaces by IDs.
-impl Manager {
-	fn new(config: ManagerConfig) -> std::io::Result<Self> {
-		Ok(Self {
-			state: ManagerState {
-				config,
+		for layer in &shell.layers {
+			tracing::trace!(?layer, "reassignment - processing layer");
+			let new_rect = layer.anchor.take(layer.size, &mut rect);
+			self.assign_surface(layer.surface, new_rect, !fullscreen, dirty_surfaces);
+		}
 
-				id_counter: Id::START,
+		if let Some(wallpaper) = shell.wallpaper {
+			let visible = shell.root.is_none() && !fullscreen;
+			self.assign_surface(wallpaper, rect, visible, dirty_surfaces);
+		}
 
-				surfaces: HashMap::new(),
-				tasks: HashMap::new(),
-				keyboard_focused_container: None,
-			},
+		if let Some(root) = &mut shell.root {
+			self.reassign_container(root, &rect, true, dirty_surfaces);
+		}
+	}
+
+	/// Set the area and visibility of a surface, adding it to `dirty_surfaces` if they changed.
+	///
+	/// The buffers are resized here, so that a surface never has buffers of another size than its area,
+	/// even if its description is not sent right away.
+	fn assign_surface(
+		&mut self,
+		id: SurfaceId,
+		base_rect: Rectangle,
+		visible: bool,
+		dirty_surfaces: &mut Vec<SurfaceId>,
+	) {
+		let surface = self.surfaces.get_mut(&id).unwrap();
+		let old = surface.description;
+		surface.description.base_rect = base_rect;
+		surface.description.visible = visible;
+		surface.resize_buffers(id);
+		if old != surface.description && !dirty_surfaces.contains(&id) {
+			dirty_surfaces.push(id);
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Manager {
+	state: ManagerState,
+	shell: Shell,
+	input: Input,
+}
+
+enum ManagerCommand {
+	CreateSurface {
+		task: TaskId,
+		serial: Serial,
+		options: SurfaceInit,
+	},
+	DestroySurface {
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+	},
+	ListSurfaces {
+		task: TaskId,
+		serial: Serial,
+	},
+	Commit {
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		generation: u32,
+		damage: Vec<Rectangle>,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+	},
+	SetTitle {
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		title: Option<Box<str>>,
+	},
+	SetAppId {
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		app_id: Option<Box<str>>,
+	},
+	OfferSelection {
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_types: Vec<Box<str>>,
+	},
+	RequestSelection {
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_type: Box<str>,
+	},
+	SendSelection {
+		task: TaskId,
+		serial: Serial,
+		transfer: TransferId,
+		data: Option<Vec<u8>>,
+	},
+	Screenshot {
+		task: TaskId,
+		serial: Serial,
+		surface: Option<SurfaceId>,
+	},
+	RequestFullscreen {
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		fullscreen: bool,
+	},
+	RemoveTask {
+		task: TaskId,
+	},
+}
+
+#[derive(Clone)]
+struct ManagerHandle {
+	channel: mpsc::Sender<ManagerCommand>,
+}
+
+// TODO: Avoid unwraps when getting tasks and surfaces by IDs.
+impl Manager {
+	fn new(config: ManagerConfig) -> std::io::Result<Self> {
+		Ok(Self {
+			state: ManagerState::new(config),
 			shell: Shell {
 				layers: Vec::new(),
 				root: None,
@@ -316,23 +1224,18 @@ impl Manager {
 		})
 	}
 
-	fn prune_shell(&mut self) {
-		tracing::trace!(?self.shell, ?self.state.keyboard_focused_container, "prune shell - before");
-		self
-			.shell
-			.retain(|surface| self.state.surfaces.contains_key(&surface));
-		self
-			.shell
-			.fix_path(&mut self.state.keyboard_focused_container);
-		tracing::trace!(?self.shell, ?self.state.keyboard_focused_container, "prune shell - after");
-	}
-
 	fn remove_task_(&mut self, id: TaskId) {
 		let Some(_) = self.state.tasks.remove(&id) else {
 			return;
 		};
 		self.state.surfaces.retain(|_, surface| surface.task != id);
-		self.prune_shell();
+		self.state.selections.retain(|_, owner| owner.task != id);
+		// Transfers that the task was supposed to answer are failed by `fail_orphaned_transfers`.
+		self
+			.state
+			.transfers
+			.retain(|_, transfer| transfer.requester != id);
+		self.state.prune_shell(&mut self.shell);
 	}
 
 	async fn remove_surface_(&mut self, id: SurfaceId) -> Result<(), ()> {
@@ -343,10 +1246,13 @@ impl Manager {
 		let task = self.state.tasks.get(&task_id).unwrap();
 		if task
 			.channel
-			.send(Event::Surface {
-				id,
-				event: SurfaceEvent::Quit,
-			})
+			.send(
+				Event::Surface {
+					id,
+					event: SurfaceEvent::Quit,
+				}
+				.into(),
+			)
 			.await
 			.is_err()
 		{
@@ -359,7 +1265,7 @@ impl Manager {
 
 	async fn remove_surface(&mut self, id: SurfaceId) -> Result<(), ()> {
 		self.remove_surface_(id).await?;
-		self.prune_shell();
+		self.state.prune_shell(&mut self.shell);
 		self.reassign_areas().await;
 		Ok(())
 	}
@@ -367,55 +1273,73 @@ impl Manager {
 	/// Since the removal of the task's surfaces may affect layout, this calls `reassign_areas`.
 	async fn remove_task(&mut self, id: TaskId) {
 		self.remove_task_(id);
+		self.fail_orphaned_transfers().await;
 		self.reassign_areas().await;
 	}
 
+	/// Fail transfers whose owner has been removed.
+	async fn fail_orphaned_transfers(&mut self) {
+		let tasks = &self.state.tasks;
+		let orphaned: Vec<_> = self
+			.state
+			.transfers
+			.iter()
+			.filter(|(_, transfer)| !tasks.contains_key(&transfer.owner))
+			.map(|(&id, _)| id)
+			.collect();
+		for id in orphaned {
+			let transfer = self.state.transfers.remove(&id).unwrap();
+			tracing::trace!(?id, ?transfer, "owner of transfer was removed");
+			let task = self.state.tasks.get(&transfer.requester).unwrap();
+			let event = Event::Ack {
+				serial: transfer.serial,
+				result: Err(CommandError::SelectionUnavailable),
+			};
+			// As in `sync_focus`, a failed send will be followed by a `RemoveTask` command.
+			_ = task.channel.send(event.into()).await;
+		}
+	}
+
 	async fn reassign_areas(&mut self) {
 		tracing::trace!("reassign areas");
+		// The focus determines which children of tabbed and stacked containers are visible.
+		self.record_focus();
+		if self.state.fullscreen_surface.is_some()
+			&& self.state.fullscreen_surface != self.focused_surface()
+		{
+			tracing::trace!("fullscreen surface lost focus");
+			self.state.fullscreen_surface = None;
+			self.state.margins_dirty = true;
+		}
 		let mut dirty_surfaces = Vec::new();
 		'outer: loop {
-			dirty_surfaces.clear();
-
-			let mut rect = Rectangle::new(Pos2::ZERO, Framebuffer::SIZE).inset(self.state.config.inset);
-
-			for layer in &self.shell.layers {
-				tracing::trace!(?layer, "reassignment - processing layer");
-				let surface_id = layer.surface;
-				let new_rect = layer.anchor.take(layer.size, &mut rect);
-				let surface = self.state.surfaces.get_mut(&surface_id).unwrap();
-				if new_rect != surface.description.base_rect {
-					dirty_surfaces.push(surface_id);
-				}
-				surface.description.base_rect = new_rect;
-			}
-
-			if let Some(wallpaper) = self.shell.wallpaper {
-				let surface = self.state.surfaces.get_mut(&wallpaper).unwrap();
-				let old = surface.description;
-				surface.description.base_rect = rect;
-				surface.description.visible = self.shell.root.is_none();
-				if old != surface.description {
-					dirty_surfaces.push(wallpaper);
-				}
-			}
-
-			if let Some(root) = &mut self.shell.root {
-				self
-					.state
-					.reassign_container(root, &rect, &mut dirty_surfaces);
-			};
+			self
+				.state
+				.assign_areas(&mut self.shell, &mut dirty_surfaces);
+			// Surfaces that were dirty in an interrupted pass stay dirty, unless their task was removed.
+			dirty_surfaces.retain(|id| self.state.surfaces.contains_key(id));
 
 			tracing::trace!(num_dirty=?dirty_surfaces.len(), "processing dirty surfaces");
-			for &surface_id in &dirty_surfaces {
-				let surface = self.state.surfaces.get(&surface_id).unwrap();
+			while !dirty_surfaces.is_empty() {
+				let surface_id = dirty_surfaces.remove(0);
+				let surface = self.state.surfaces.get_mut(&surface_id).unwrap();
 				tracing::trace!(?surface_id, ?surface, "processing dirty surface");
 				let task_id = surface.task;
-				let task = self.state.tasks.get(&task_id).unwrap();
-				let event = Event::Surface {
+				// The client needs buffers of the new size before it can draw for the new description.
+				let buffers_event = surface.unsent_buffers.take();
+				let description_event = Event::Surface {
 					id: surface_id,
 					event: SurfaceEvent::Description(surface.description),
 				};
-				if task.channel.send(event).await.is_err() {
+				let task = self.state.tasks.get(&task_id).unwrap();
+				let mut res = Ok(());
+				for event in buffers_event.into_iter().chain([description_event.into()]) {
+					res = task.channel.send(event).await;
+					if res.is_err() {
+						break;
+					}
+				}
+				if res.is_err() {
 					self.remove_task_(task_id);
 					// We need to restart the assignment because previously processed surfaces may have also been owned by this task and thus removed.
 					// The check for a differing `base_rect` should avoid repetition of surface assignments to clients.
@@ -427,23 +1351,112 @@ impl Manager {
 			}
 			break;
 		}
+
+		// Layout changes may have changed the focused surface.
+		self.sync_focus().await;
+	}
+
+	/// Remember the focused node in its ancestors, so that focus can return to it.
+	fn record_focus(&mut self) {
+		if let (Some(root), Some(path)) = (&mut self.shell.root, &self.state.keyboard_focused_container)
+		{
+			root.record_focus(path);
+		}
+	}
+
+	/// Send `FocusOut` and `FocusIn` events if the focused surface has changed since the last call.
+	async fn sync_focus(&mut self) {
+		self.record_focus();
+
+		let focused = self.focused_surface();
+		let old = std::mem::replace(&mut self.state.keyboard_focused_surface, focused);
+		if old == focused {
+			return;
+		}
+		tracing::trace!(?old, ?focused, "keyboard focus changed");
+
+		for (surface_id, event) in [
+			(old, SurfaceEvent::FocusOut),
+			(focused, SurfaceEvent::FocusIn),
+		] {
+			// The previously focused surface may have been removed.
+			let Some(surface) = surface_id.and_then(|id| self.state.surfaces.get(&id)) else {
+				continue;
+			};
+			let task = self.state.tasks.get(&surface.task).unwrap();
+			let event = Event::Surface {
+				id: surface_id.unwrap(),
+				event,
+			};
+			// If this fails, the task's loop has ended and it will be removed by a `RemoveTask` command.
+			// Removing it here would recurse through `reassign_areas`.
+			_ = task.channel.send(event.into()).await;
+		}
+
+		self.broadcast_focused_surface_info().await;
+	}
+
+	fn focused_surface_info(&self) -> Option<SurfaceInfo> {
+		let surface = self
+			.state
+			.surfaces
+			.get(&self.state.keyboard_focused_surface?)?;
+		Some(surface.info.clone())
+	}
+
+	/// Send an `Event::FocusedSurface` to every task.
+	///
+	/// The event is only written to clients that negotiated `Capability::FocusedSurfaceInfo`.
+	async fn broadcast_focused_surface_info(&self) {
+		let info = self.focused_surface_info();
+		for task in self.state.tasks.values() {
+			// As in `sync_focus`, a failed send will be followed by a `RemoveTask` command.
+			_ = task
+				.channel
+				.send(Event::FocusedSurface(info.clone()).into())
+				.await;
+		}
+	}
+
+	async fn send_surface_event(&mut self, id: SurfaceId, event: SurfaceEvent) {
+		let task_id = self.state.surfaces.get(&id).unwrap().task;
+		_ = self.send_event(task_id, Event::Surface { id, event }).await;
 	}
 
 	async fn spawn_task(
 		&mut self,
 		client: tokio::net::UnixStream,
 		handle: ManagerHandle,
-	) -> (TaskId, mpsc::Sender<Event>) {
-		let (event_send, mut event_recv) = mpsc::channel(2);
+	) -> (TaskId, mpsc::Sender<Outgoing>) {
+		let (event_send, mut event_recv) = mpsc::channel::<Outgoing>(2);
 		let task_id = TaskId(self.state.next_id());
 		tokio::spawn(async move {
-			let client = rmox_protocol::io::Stream::new(client);
+			// The WM supports every capability of the protocol version it was built with.
+			let (client, capabilities) =
+				match rmox_protocol::handshake::server(UnixTransport::new(client), Capabilities::all())
+					.await
+				{
+					Ok(res) => res,
+					Err(error) => {
+						tracing::warn!(?task_id, ?error, "handshake with client failed");
+						handle.remove_task(task_id).await;
+						return;
+					}
+				};
+			tracing::debug!(?task_id, ?capabilities, "handshake with client succeeded");
 			pin!(client);
 			loop {
 				select! {
-					Some(event) = event_recv.recv() => {
-						tracing::debug!(?task_id, ?event, "received event for client");
-						let res = client.write(&event).await;
+					Some(Outgoing { event, fd }) = event_recv.recv() => {
+						if event.capability().is_some_and(|capability| !capabilities.contains(capability)) {
+							tracing::trace!(?task_id, ?event, "client does not support event, not sending");
+							continue;
+						}
+						tracing::debug!(?task_id, ?event, ?fd, "received event for client");
+						let res = match &fd {
+							Some(fd) => client.write_with_fds(&event, &[fd.as_fd()]).await,
+							None => client.write(&event).await,
+						};
 						if let Err(error) = res {
 							tracing::warn!(?task_id, ?error, "error writing to client");
 							break;
@@ -451,11 +1464,41 @@ impl Manager {
 					}
 					res = client.next() => {
 						match res {
-							Some(Ok(command)) => {
-								tracing::debug!(?task_id, ?command, "received command from client");
+							Some(Ok(Request { serial, command })) => {
+								tracing::debug!(?task_id, ?serial, ?command, "received command from client");
 								match command {
 									Command::CreateSurface(options) => {
-										handle.create_surface(task_id, options).await;
+										handle.create_surface(task_id, serial, options).await;
+									}
+									Command::DestroySurface(surface) => {
+										handle.destroy_surface(task_id, serial, surface).await;
+									}
+									Command::ListSurfaces => {
+										handle.list_surfaces(task_id, serial).await;
+									}
+									Command::Commit { surface, generation, damage, style, depth } => {
+										handle.commit(task_id, serial, surface, generation, damage, style, depth).await;
+									}
+									Command::SetTitle { surface, title } => {
+										handle.set_title(task_id, serial, surface, title).await;
+									}
+									Command::SetAppId { surface, app_id } => {
+										handle.set_app_id(task_id, serial, surface, app_id).await;
+									}
+									Command::OfferSelection { selection, mime_types } => {
+										handle.offer_selection(task_id, serial, selection, mime_types).await;
+									}
+									Command::RequestSelection { selection, mime_type } => {
+										handle.request_selection(task_id, serial, selection, mime_type).await;
+									}
+									Command::SendSelection { transfer, data } => {
+										handle.send_selection(task_id, serial, transfer, data).await;
+									}
+									Command::Screenshot { surface } => {
+										handle.screenshot(task_id, serial, surface).await;
+									}
+									Command::RequestFullscreen { surface, fullscreen } => {
+										handle.request_fullscreen(task_id, serial, surface, fullscreen).await;
 									}
 								}
 							}
@@ -479,22 +1522,66 @@ impl Manager {
 			},
 		);
 
+		// Let the client know the current state; it is only written if the client negotiates the capability.
+		// The channel is new, so this cannot block.
+		_ = event_send
+			.send(Event::FocusedSurface(self.focused_surface_info()).into())
+			.await;
+
 		(task_id, event_send)
 	}
 
-	async fn create_surface(&mut self, task: TaskId, options: SurfaceInit) {
-		tracing::trace!(?task, ?options, "create surface");
+	/// If the task's channel is closed, the task is removed and `Err` is returned.
+	async fn send_event(&mut self, task_id: TaskId, event: Event) -> Result<(), ()> {
+		let task = self.state.tasks.get(&task_id).unwrap();
+		if task.channel.send(event.into()).await.is_err() {
+			self.remove_task(task_id).await;
+			return Err(());
+		}
+		Ok(())
+	}
+
+	/// Reply to the request with the given `serial`.
+	///
+	/// If the task's channel is closed, the task is removed and `Err` is returned.
+	async fn ack(
+		&mut self,
+		task_id: TaskId,
+		serial: Serial,
+		result: Result<Reply, CommandError>,
+	) -> Result<(), ()> {
+		tracing::trace!(?task_id, ?serial, ?result, "ack");
+		self
+			.send_event(task_id, Event::Ack { serial, result })
+			.await
+	}
+
+	async fn create_surface(&mut self, task: TaskId, serial: Serial, options: SurfaceInit) {
+		tracing::trace!(?task, ?serial, ?options, "create surface");
+		// The task may have been removed while this command was queued.
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		if let SurfaceInit::Layer { size, .. } = options {
+			if size <= 0 {
+				_ = self
+					.ack(task, serial, Err(CommandError::InvalidLayerSize))
+					.await;
+				return;
+			}
+		}
+
 		let surface_id = SurfaceId(self.state.next_id());
-		let surface = Surface {
-			description: SurfaceDescription {
-				// Will be set by `reassign_areas`.
-				base_rect: Rectangle::ZERO,
-				rotation: self.state.config.global_rotation,
-				scale: 1,
-				visible: true,
-			},
-			task,
-		};
+		if self
+			.ack(task, serial, Ok(Reply::SurfaceCreated(surface_id)))
+			.await
+			.is_err()
+		{
+			return;
+		}
+
+		let surface = Surface::new(task, self.state.config.global_rotation);
 		self.state.surfaces.insert(surface_id, surface);
 
 		match options {
@@ -512,17 +1599,16 @@ impl Manager {
 
 				if let Some(root) = &mut self.shell.root {
 					let path = self.state.keyboard_focused_container.as_mut().unwrap();
-					// Get the container of the currently focused node by removing the last path segment.
-					let container = root.get_container_mut(&path[..path.len() - 1]).unwrap();
-					container.children.push(ShellNode::Surface(surface_id));
-					*path.last_mut().unwrap() = (container.children.len() - 1).try_into().unwrap();
+					// Add the surface next to the focused node, or into the root container if it is focused.
+					path.pop();
+					let container = root.get_container_mut(path).unwrap();
+					container.insert(container.children.len(), ShellNode::Surface(surface_id));
+					path.push((container.children.len() - 1).try_into().unwrap());
 				} else {
-					self.shell.root = Some(Container {
-						// Will be set by `reassign_areas`.
-						rect: Rectangle::ZERO,
-						kind: ContainerKind::Horizontal,
-						children: vec![ShellNode::Surface(surface_id)],
-					});
+					self.shell.root = Some(Container::new(
+						ContainerKind::Horizontal,
+						ShellNode::Surface(surface_id),
+					));
 					self.state.keyboard_focused_container = Some(vec![0]);
 				}
 			}
@@ -537,49 +1623,728 @@ impl Manager {
 		self.reassign_areas().await;
 	}
 
-	// TODO: If a parent container of a surface is focused,
-	// there may be some situations where we want to force the focus to one of the child surfaces,
-	// e.g., if the user types on the keyboard.
-	// Not sure if jumping to a child surface is better or worse than ignoring the keyboard input entirely.
-	fn focused_surface(&self) -> Option<SurfaceId> {
-		if let Some(ShellNode::Surface(surface_id)) = self
-			.state
-			.keyboard_focused_container
-			.as_deref()
-			.and_then(|path| self.shell.get_path(path))
-		{
-			Some(*surface_id)
-		} else {
-			None
+	async fn destroy_surface(&mut self, task: TaskId, serial: Serial, surface_id: SurfaceId) {
+		tracing::trace!(?task, ?serial, ?surface_id, "destroy surface");
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let owned = self
+			.state
+			.surfaces
+			.get(&surface_id)
+			.is_some_and(|surface| surface.task == task);
+		if !owned {
+			_ = self
+				.ack(task, serial, Err(CommandError::UnknownSurface))
+				.await;
+			return;
+		}
+
+		if self.remove_surface(surface_id).await.is_err() {
+			// The task was removed.
+			return;
+		}
+		_ = self.ack(task, serial, Ok(Reply::Done)).await;
+	}
+
+	async fn list_surfaces(&mut self, task: TaskId, serial: Serial) {
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let surfaces = self
+			.state
+			.surfaces
+			.iter()
+			.filter(|(_, surface)| surface.task == task)
+			.map(|(&id, _)| id)
+			.collect();
+		_ = self.ack(task, serial, Ok(Reply::Surfaces(surfaces))).await;
+	}
+
+	#[allow(clippy::too_many_arguments)] // Mirrors `Command::Commit`.
+	async fn commit(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		surface_id: SurfaceId,
+		generation: u32,
+		damage: &[Rectangle],
+		style: UpdateStyle,
+		depth: UpdateDepth,
+		fb: &mut Framebuffer,
+	) {
+		tracing::trace!(
+			?task,
+			?serial,
+			?surface_id,
+			generation,
+			?damage,
+			?style,
+			?depth,
+			"commit"
+		);
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let Some(surface) = self
+			.state
+			.surfaces
+			.get_mut(&surface_id)
+			.filter(|surface| surface.task == task)
+		else {
+			_ = self
+				.ack(task, serial, Err(CommandError::UnknownSurface))
+				.await;
+			return;
+		};
+		let Some(buffers) = &mut surface.buffers else {
+			_ = self.ack(task, serial, Err(CommandError::NoBuffers)).await;
+			return;
+		};
+		// If the client drew into buffers that it has since replaced, flipping ours would put the two sides out of step.
+		// The buffers are resized along with the area, so they should always match it, but copying them must not crash the WM.
+		if generation != surface.buffers_generation
+			|| buffers.size() != surface.description.base_rect.size
+		{
+			_ = self
+				.ack(task, serial, Err(CommandError::StaleBuffers))
+				.await;
+			return;
+		}
+
+		// The damage is relative to the buffers, which cover the surface's area.
+		// Clipping it to the area ensures that the client cannot affect anything outside of it.
+		let area = surface.description.base_rect;
+		let damage: Vec<Rectangle> = damage
+			.iter()
+			.map(|rect| Rectangle::new(rect.origin + area.origin.to_vec(), rect.size).intersection(&area))
+			.filter(|rect| !rect.is_empty())
+			.collect();
+		if surface.description.visible {
+			for rect in &damage {
+				fb.copy_from(&area, buffers.back(), rect);
+			}
+		}
+		// The client flips its buffers as soon as it commits, so we must flip ours regardless of whether the buffer was shown.
+		buffers.flip();
+
+		if surface.description.visible {
+			for rect in &damage {
+				let res = fb
+					.update_async(rect, style, depth, UpdateOptions::default())
+					.await;
+				if let Err(error) = res {
+					tracing::warn!(?surface_id, ?error, "updating committed surface");
+				}
+			}
+		}
+		_ = self.ack(task, serial, Ok(Reply::Done)).await;
+	}
+
+	async fn set_surface_info(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		surface_id: SurfaceId,
+		update: impl FnOnce(&mut SurfaceInfo),
+	) {
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let Some(surface) = self
+			.state
+			.surfaces
+			.get_mut(&surface_id)
+			.filter(|surface| surface.task == task)
+		else {
+			_ = self
+				.ack(task, serial, Err(CommandError::UnknownSurface))
+				.await;
+			return;
+		};
+		update(&mut surface.info);
+		tracing::trace!(?surface_id, info = ?surface.info, "surface info changed");
+
+		if self.ack(task, serial, Ok(Reply::Done)).await.is_err() {
+			return;
+		}
+		if self.state.keyboard_focused_surface == Some(surface_id) {
+			self.broadcast_focused_surface_info().await;
+		}
+	}
+
+	async fn offer_selection(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_types: Vec<Box<str>>,
+	) {
+		tracing::trace!(?task, ?serial, ?selection, ?mime_types, "offer selection");
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let old = self
+			.state
+			.selections
+			.insert(selection, SelectionOwner { task, mime_types });
+		if self.ack(task, serial, Ok(Reply::Done)).await.is_err() {
+			return;
+		}
+		if let Some(old) = old.filter(|old| old.task != task) {
+			_ = self
+				.send_event(old.task, Event::SelectionCancelled { selection })
+				.await;
+		}
+	}
+
+	async fn request_selection(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_type: Box<str>,
+	) {
+		tracing::trace!(?task, ?serial, ?selection, ?mime_type, "request selection");
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let Some(owner) = self
+			.state
+			.selections
+			.get(&selection)
+			.filter(|owner| owner.mime_types.contains(&mime_type))
+		else {
+			_ = self.ack(task, serial, Err(CommandError::NoSelection)).await;
+			return;
+		};
+		let owner = owner.task;
+
+		let transfer = TransferId(self.state.next_id());
+		self.state.transfers.insert(
+			transfer,
+			Transfer {
+				owner,
+				requester: task,
+				serial,
+			},
+		);
+		let event = Event::SelectionRequest {
+			transfer,
+			selection,
+			mime_type,
+		};
+		// If this fails, the owner is removed and the transfer is failed by `fail_orphaned_transfers`.
+		_ = self.send_event(owner, event).await;
+	}
+
+	async fn send_selection(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		transfer_id: TransferId,
+		data: Option<Vec<u8>>,
+	) {
+		tracing::trace!(?task, ?serial, ?transfer_id, "send selection");
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let owned = self
+			.state
+			.transfers
+			.get(&transfer_id)
+			.is_some_and(|transfer| transfer.owner == task);
+		if !owned {
+			_ = self
+				.ack(task, serial, Err(CommandError::UnknownTransfer))
+				.await;
+			return;
+		}
+		let transfer = self.state.transfers.remove(&transfer_id).unwrap();
+
+		// The requester should get its data even if the owner has disconnected in the meantime.
+		_ = self.ack(task, serial, Ok(Reply::Done)).await;
+		if !self.state.tasks.contains_key(&transfer.requester) {
+			return;
+		}
+		let result = data
+			.map(Reply::SelectionData)
+			.ok_or(CommandError::SelectionUnavailable);
+		_ = self.ack(transfer.requester, transfer.serial, result).await;
+	}
+
+	async fn screenshot(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		surface_id: Option<SurfaceId>,
+		fb: &Framebuffer,
+	) {
+		tracing::trace!(?task, ?serial, ?surface_id, "screenshot");
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let mut description = match surface_id {
+			Some(surface_id) => {
+				let Some(surface) = self.state.surfaces.get(&surface_id) else {
+					_ = self
+						.ack(task, serial, Err(CommandError::UnknownSurface))
+						.await;
+					return;
+				};
+				if !surface.description.visible {
+					_ = self
+						.ack(task, serial, Err(CommandError::SurfaceNotVisible))
+						.await;
+					return;
+				}
+				surface.description
+			}
+			None => SurfaceDescription {
+				base_rect: fb.rect(),
+				rotation: self.state.config.global_rotation,
+				scale: 1,
+				visible: true,
+			},
+		};
+		// Capture at the full resolution of the display.
+		description.scale = 1;
+
+		let screenshot = fb.read_mapped(description.size(), |point| {
+			description.transform_point(point)
+		});
+		// Encoding a whole screen takes a while, so encode and reply in the background to avoid holding up the manager.
+		let channel = self.state.tasks.get(&task).unwrap().channel.clone();
+		tokio::spawn(async move {
+			let result = tokio::task::spawn_blocking(move || screenshot.encode_png())
+				.await
+				.unwrap();
+			let result = result.map(Reply::Screenshot).map_err(|error| {
+				tracing::warn!(?error, "encoding screenshot");
+				CommandError::ScreenshotFailed
+			});
+			tracing::trace!(?task, ?serial, "screenshot ack");
+			// As in `sync_focus`, a failed send will be followed by a `RemoveTask` command.
+			_ = channel.send(Event::Ack { serial, result }.into()).await;
+		});
+	}
+
+	/// If a container is focused, this is its most recently focused surface,
+	/// so that keyboard input is not lost while the user rearranges containers.
+	fn focused_surface(&self) -> Option<SurfaceId> {
+		let path = self.state.keyboard_focused_container.as_deref()?;
+		if let Some(ShellNode::Surface(surface_id)) = self.shell.get_path(path) {
+			return Some(*surface_id);
+		}
+		self
+			.shell
+			.get_container(path)
+			.map(Container::focused_surface)
+	}
+
+	fn surface_at(&self, point: Pos2) -> Option<SurfaceId> {
+		if let Some(fullscreen) = self.state.fullscreen_surface {
+			return Some(fullscreen);
+		}
+		self.shell.surface_at(point, |id| {
+			self.state.surfaces.get(&id).unwrap().description.base_rect
+		})
+	}
+
+	fn touch_target(&mut self, event: &rmox_input::touch::Event) -> Option<SurfaceId> {
+		match event.phase {
+			rmox_input::touch::Phase::Start => {
+				let position = self
+					.input
+					.touch_state(event.touch_id)?
+					.position(self.state.config.screen_size);
+				let surface_id = self.surface_at(position)?;
+				self.state.touch_grabs.insert(event.touch_id, surface_id);
+				Some(surface_id)
+			}
+			rmox_input::touch::Phase::Change => self.state.touch_grabs.get(&event.touch_id).copied(),
+			rmox_input::touch::Phase::End => self.state.touch_grabs.remove(&event.touch_id),
+		}
+	}
+
+	/// If the stylus moved from one surface to another, this sends `Leave` to the old surface and changes `event` to `Hover` so the new surface sees it enter.
+	async fn stylus_target(&mut self, event: &mut rmox_input::stylus::Event) -> Option<SurfaceId> {
+		use rmox_input::stylus::Phase;
+
+		let state = self.input.stylus_state();
+		let surface_id = match event.phase {
+			// While touching, the stylus stays with the surface it touched down on.
+			Phase::Change if state.is_some_and(|state| state.touching()) => self.state.stylus_surface,
+			Phase::Lift | Phase::Leave => self.state.stylus_surface,
+			Phase::Hover | Phase::Touch | Phase::Change => {
+				state.and_then(|state| self.surface_at(state.position(self.state.config.screen_size)))
+			}
+		};
+		let new = match event.phase {
+			Phase::Leave => None,
+			_ => surface_id,
+		};
+		let old = std::mem::replace(&mut self.state.stylus_surface, new);
+
+		if matches!(event.phase, Phase::Hover | Phase::Touch | Phase::Change) && old != surface_id {
+			tracing::trace!(?old, new=?surface_id, "stylus crossed surfaces");
+			if let Some(old) = old {
+				let leave = SurfaceEvent::Input(InputEvent::Stylus(StylusEvent {
+					phase: StylusPhase::Leave,
+				}));
+				self.send_surface_event(old, leave).await;
+			}
+			if event.phase == Phase::Change {
+				event.phase = Phase::Hover;
+			}
+		}
+
+		surface_id
+	}
+
+	async fn move_focus(&mut self, mut direction: Side) {
+		// Focus can only leave a fullscreen surface by toggling fullscreen or creating a surface.
+		if self.state.fullscreen_surface.is_some() {
+			return;
+		}
+		direction = direction.rotate(self.state.config.global_rotation);
+		if let Some(root) = &mut self.shell.root {
+			if let Some(path) = &self.state.keyboard_focused_container {
+				if let Some(node) = root.get_path(path) {
+					let rect = match node {
+						ShellNode::Container(container) => container.rect,
+						ShellNode::Surface(id) => self.state.surfaces.get(id).unwrap().description.base_rect,
+					};
+					let test_point = rect
+						.midpoint(direction)
+						.offset(direction, 1)
+						.wrap_within(&root.rect);
+					if let Some(path) = self.shell.point_to_path(test_point, |id| {
+						self.state.surfaces.get(&id).unwrap().description.base_rect
+					}) {
+						self.state.keyboard_focused_container = Some(path);
+					} else {
+						tracing::warn!(?test_point, "point_to_path returned None");
+					}
+				}
+			}
+		}
+		self.sync_focus().await;
+	}
+
+	async fn focus_parent(&mut self) {
+		if let Some(path) = &mut self.state.keyboard_focused_container {
+			// Does nothing if the root container is already focused.
+			path.pop();
+		}
+		self.sync_focus().await;
+	}
+
+	async fn focus_child(&mut self) {
+		if let Some(path) = &mut self.state.keyboard_focused_container {
+			if let Some(container) = self.shell.get_container(path) {
+				path.push(container.focused_child());
+			}
+		}
+		self.sync_focus().await;
+	}
+
+	/// Place the focused node into a new container of `kind`, so that surfaces created while it is focused are placed next to it.
+	///
+	/// If the node has no siblings, its parent is changed to `kind` instead of nesting another container.
+	async fn split_focused(&mut self, kind: ContainerKind) {
+		let (Some(root), Some(path)) = (
+			&mut self.shell.root,
+			&mut self.state.keyboard_focused_container,
+		) else {
+			return;
+		};
+
+		if let Some((&index, parent_path)) = path.split_last() {
+			let parent = root.get_container_mut(parent_path).unwrap();
+			if parent.children.len() == 1 {
+				parent.kind = kind;
+			} else {
+				// The new container takes the place of the node, including its weight.
+				let node = &mut parent.children[usize::from(index)].node;
+				let old = std::mem::replace(node, ShellNode::Container(Container::placeholder()));
+				*node = ShellNode::Container(Container::new(kind, old));
+				path.push(0);
+			}
+		} else {
+			let old = std::mem::replace(root, Container::placeholder());
+			*root = Container::new(kind, ShellNode::Container(old));
+			path.push(0);
+		}
+
+		self.reassign_areas().await;
+	}
+
+	/// Change the kind of the focused container, or of the parent of the focused surface.
+	async fn set_focused_container_kind(&mut self, kind: ContainerKind) {
+		let (Some(root), Some(path)) = (&mut self.shell.root, &self.state.keyboard_focused_container)
+		else {
+			return;
+		};
+		let path = root.container_path(path).to_owned();
+		if let Some(container) = root.get_container_mut(&path) {
+			container.kind = kind;
+			self.reassign_areas().await;
+		}
+	}
+
+	/// Grow or shrink the focused node relative to its siblings.
+	///
+	/// If the focused node has no siblings, its nearest ancestor that does is resized instead.
+	async fn resize_focused(&mut self, grow: bool) {
+		let (Some(root), Some(path)) = (&mut self.shell.root, &self.state.keyboard_focused_container)
+		else {
+			return;
+		};
+		let Some(depth) = (1..=path.len()).rev().find(|&depth| {
+			// Tabbed and stacked children are not sized relative to each other.
+			let container = root.get_container(&path[..depth - 1]).unwrap();
+			container.kind.is_split() && container.children.len() > 1
+		}) else {
+			return;
+		};
+
+		let container = root.get_container_mut(&path[..depth - 1]).unwrap();
+		let side = container
+			.kind
+			.start_side()
+			.rotate(self.state.config.global_rotation);
+		let (numerator, denominator) = if grow { (5, 4) } else { (4, 5) };
+		if container.scale_weight(path[depth - 1], numerator, denominator, side) {
+			self.reassign_areas().await;
+		}
+	}
+
+	/// Let touches and the stylus drag the borders between the children of containers to resize them.
+	///
+	/// The layout is only updated once a drag ends,
+	/// since every update makes the affected surfaces reallocate their buffers and redraw.
+	///
+	/// Returns whether the event was part of a drag, in which case it should not be sent to any surface.
+	async fn handle_border_drag(&mut self, event: &rmox_input::Event) -> bool {
+		let rotation = self.state.config.global_rotation;
+		let grab = match event {
+			rmox_input::Event::Touch(event) => {
+				use rmox_input::touch::Phase;
+
+				let position = self
+					.input
+					.touch_state(event.touch_id)
+					.map(|state| state.position(self.state.config.screen_size));
+				let grabs = &mut self.state.touch_border_grabs;
+				match (event.phase, position) {
+					(Phase::Start, Some(position)) => {
+						let Some(grab) = self.shell.border_at(position, rotation) else {
+							return false;
+						};
+						tracing::trace!(?grab, "touch grabbed border");
+						grabs.insert(event.touch_id, grab);
+						return true;
+					}
+					(Phase::Change, Some(position)) => {
+						let Some(grab) = grabs.get_mut(&event.touch_id) else {
+							return false;
+						};
+						grab.position = position;
+						return true;
+					}
+					(Phase::End, _) => grabs.remove(&event.touch_id),
+					_ => return grabs.contains_key(&event.touch_id),
+				}
+			}
+			rmox_input::Event::Stylus(event) => {
+				use rmox_input::stylus::Phase;
+
+				let position = self
+					.input
+					.stylus_state()
+					.map(|state| state.position(self.state.config.screen_size));
+				let grab = &mut self.state.stylus_border_grab;
+				match (event.phase, position) {
+					(Phase::Touch, Some(position)) => {
+						*grab = self.shell.border_at(position, rotation);
+						tracing::trace!(?grab, "stylus grabbed border");
+						return grab.is_some();
+					}
+					(Phase::Change, Some(position)) => {
+						let Some(grab) = grab else {
+							return false;
+						};
+						grab.position = position;
+						return true;
+					}
+					(Phase::Lift | Phase::Leave, _) => grab.take(),
+					_ => return grab.is_some(),
+				}
+			}
+			_ => return false,
+		};
+		let Some(grab) = grab else {
+			return false;
+		};
+
+		tracing::trace!(?grab, "border dropped");
+		if let Some(container) = self
+			.shell
+			.root
+			.as_mut()
+			.and_then(|root| root.get_container_mut(&grab.container))
+		{
+			let side = container.kind.start_side().rotate(rotation);
+			container.drag_border(grab.index, grab.position, side);
+			self.reassign_areas().await;
+		}
+		true
+	}
+
+	/// Make `surface_id` fullscreen and focus it, or return it to the layout.
+	async fn set_fullscreen(&mut self, surface_id: SurfaceId, fullscreen: bool) -> Result<(), ()> {
+		self
+			.state
+			.set_fullscreen(&self.shell, surface_id, fullscreen)?;
+		self.reassign_areas().await;
+		Ok(())
+	}
+
+	async fn toggle_fullscreen(&mut self) {
+		let Some(surface_id) = self.focused_surface() else {
+			return;
+		};
+		let fullscreen = self.state.fullscreen_surface != Some(surface_id);
+		_ = self.set_fullscreen(surface_id, fullscreen).await;
+	}
+
+	async fn request_fullscreen(
+		&mut self,
+		task: TaskId,
+		serial: Serial,
+		surface_id: SurfaceId,
+		fullscreen: bool,
+	) {
+		tracing::trace!(
+			?task,
+			?serial,
+			?surface_id,
+			?fullscreen,
+			"request fullscreen"
+		);
+		if !self.state.tasks.contains_key(&task) {
+			return;
+		}
+
+		let owned = self
+			.state
+			.surfaces
+			.get(&surface_id)
+			.is_some_and(|surface| surface.task == task);
+		let result = if !owned {
+			Err(CommandError::UnknownSurface)
+		} else if self.set_fullscreen(surface_id, fullscreen).await.is_err() {
+			Err(CommandError::NotNormalSurface)
+		} else {
+			Ok(Reply::Done)
+		};
+		_ = self.ack(task, serial, result).await;
+	}
+
+	/// Clear the margins around the layout if a fullscreen surface was drawn over them.
+	async fn clear_margins(&mut self, fb: &mut Framebuffer) {
+		if !std::mem::take(&mut self.state.margins_dirty) {
+			return;
+		}
+		tracing::trace!("clearing margins");
+
+		let mut rect = Rectangle::new(Pos2::ZERO, self.state.config.screen_size);
+		for side in Side::ALL {
+			let margin = side.take(self.state.config.inset, &mut rect);
+			fb.fill_solid(&margin.into(), Rgb565::WHITE).unwrap();
+			let res = fb
+				.update_partial_async(&margin, UpdateStyle::Monochrome)
+				.await;
+			if let Err(error) = res {
+				tracing::warn!(?error, "updating margin");
+			}
+		}
+	}
+
+	/// Focus the next or previous child of the nearest tabbed or stacked container around the focused node.
+	async fn cycle_tab(&mut self, forward: bool) {
+		let (Some(root), Some(path)) = (&self.shell.root, &self.state.keyboard_focused_container)
+		else {
+			return;
+		};
+		let Some(path) = root.cycle_tab(path, forward) else {
+			return;
+		};
+		self.state.keyboard_focused_container = Some(path);
+		// Return to the surface that was last focused within the tab.
+		self
+			.shell
+			.fix_path(&mut self.state.keyboard_focused_container);
+		self.reassign_areas().await;
+	}
+
+	/// Draw the tab strips that have changed since they were last drawn.
+	///
+	/// Called after anything that may have changed the layout or titles.
+	async fn draw_tab_strips(&mut self, fb: &mut Framebuffer) {
+		let rotation = self.state.config.global_rotation;
+		let mut strips = Vec::new();
+		if let (Some(root), None) = (&self.shell.root, self.state.fullscreen_surface) {
+			let title = |id| {
+				let info = &self.state.surfaces.get(&id).unwrap().info;
+				info
+					.title
+					.as_deref()
+					.or(info.app_id.as_deref())
+					.unwrap_or("untitled")
+					.into()
+			};
+			root.tab_strips(rotation, &title, &mut strips);
 		}
-	}
 
-	async fn move_focus(&mut self, mut direction: Side) {
-		direction = direction.rotate(self.state.config.global_rotation);
-		if let Some(root) = &mut self.shell.root {
-			if let Some(path) = &self.state.keyboard_focused_container {
-				if let Some(node) = root.get_path(path) {
-					let rect = match node {
-						ShellNode::Container(container) => container.rect,
-						ShellNode::Surface(id) => self.state.surfaces.get(id).unwrap().description.base_rect,
-					};
-					let test_point = rect
-						.midpoint(direction)
-						.offset(direction, 1)
-						.wrap_within(&root.rect);
-					if let Some(path) = self.shell.point_to_path(test_point, |id| {
-						self.state.surfaces.get(&id).unwrap().description.base_rect
-					}) {
-						self.state.keyboard_focused_container = Some(path);
-					} else {
-						tracing::warn!(?test_point, "point_to_path returned None");
-					}
-				}
+		for strip in &strips {
+			if self.state.drawn_tab_strips.contains(strip) {
+				continue;
+			}
+			tracing::trace!(?strip, "drawing tab strip");
+			strip.draw(fb, rotation);
+			let res = fb
+				.update_partial_async(&strip.rect, UpdateStyle::Monochrome)
+				.await;
+			if let Err(error) = res {
+				tracing::warn!(?error, "updating tab strip");
 			}
 		}
+		self.state.drawn_tab_strips = strips;
+	}
+
+	/// Move the focused node toward `direction`, like in i3.
+	async fn move_focused(&mut self, direction: Side) {
+		let (Some(root), Some(path)) = (&mut self.shell.root, &self.state.keyboard_focused_container)
+		else {
+			return;
+		};
+		if let Some(path) = root.move_toward(path, direction) {
+			self.state.keyboard_focused_container = Some(path);
+			self.reassign_areas().await;
+		}
 	}
 
-	async fn handle_input(&mut self, event: rmox_input::Event) {
+	async fn handle_input(&mut self, mut event: rmox_input::Event) {
 		// TODO: This kind of thing should be handled by a dedicated daemon and some kind of hotkey reservation protocol.
 		if let rmox_input::Event::Key(event @ KeyEvent { key: Some(key), .. }) = &event {
 			if event.event.press() {
@@ -592,15 +2357,23 @@ impl Manager {
 						}
 						return;
 					}
+					Key::F if event.modifiers.opt() => {
+						tracing::trace!("M-f, toggling fullscreen");
+						self.toggle_fullscreen().await;
+						return;
+					}
 					Key::D if event.modifiers.opt() => {
 						tracing::trace!("M-e, changing container kind");
 						if let Some(root) = &mut self.shell.root {
 							if let Some(path) = &self.state.keyboard_focused_container {
-								// TODO: If a container itself is focused, this might be wrong.
-								if let Some(container) = root.get_container_mut(&path[..path.len() - 1]) {
+								// Change the focused container, or the parent of the focused surface.
+								let path = root.container_path(path).to_owned();
+								if let Some(container) = root.get_container_mut(&path) {
 									container.kind = match container.kind {
 										ContainerKind::Horizontal => ContainerKind::Vertical,
-										ContainerKind::Vertical => ContainerKind::Horizontal,
+										ContainerKind::Vertical | ContainerKind::Tabbed | ContainerKind::Stacked => {
+											ContainerKind::Horizontal
+										}
 									};
 									self.reassign_areas().await;
 								}
@@ -608,6 +2381,26 @@ impl Manager {
 						}
 						return;
 					}
+					Key::ArrowRight if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-right, moving focused node right");
+						self.move_focused(Side::Right).await;
+						return;
+					}
+					Key::ArrowLeft if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-left, moving focused node left");
+						self.move_focused(Side::Left).await;
+						return;
+					}
+					Key::ArrowUp if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-up, moving focused node up");
+						self.move_focused(Side::Top).await;
+						return;
+					}
+					Key::ArrowDown if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-down, moving focused node down");
+						self.move_focused(Side::Bottom).await;
+						return;
+					}
 					Key::ArrowRight if event.modifiers.opt() => {
 						tracing::trace!("M-right, moving focus right");
 						self.move_focus(Side::Right).await;
@@ -644,27 +2437,104 @@ impl Manager {
 							}
 						}
 					}
-					// TODO: Bindings for focusing parent/child container, creating nested containers.
+					Key::W if event.modifiers.opt() => {
+						tracing::trace!("M-w, making container tabbed");
+						self.set_focused_container_kind(ContainerKind::Tabbed).await;
+						return;
+					}
+					Key::S if event.modifiers.opt() => {
+						tracing::trace!("M-s, making container stacked");
+						self
+							.set_focused_container_kind(ContainerKind::Stacked)
+							.await;
+						return;
+					}
+					Key::Tab if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-tab, focusing previous tab");
+						self.cycle_tab(false).await;
+						return;
+					}
+					Key::Tab if event.modifiers.opt() => {
+						tracing::trace!("M-tab, focusing next tab");
+						self.cycle_tab(true).await;
+						return;
+					}
+					Key::Period if event.modifiers.opt() => {
+						tracing::trace!("M-., growing focused node");
+						self.resize_focused(true).await;
+						return;
+					}
+					Key::Comma if event.modifiers.opt() => {
+						tracing::trace!("M-,, shrinking focused node");
+						self.resize_focused(false).await;
+						return;
+					}
+					Key::H if event.modifiers.opt() => {
+						tracing::trace!("M-h, splitting horizontally");
+						self.split_focused(ContainerKind::Horizontal).await;
+						return;
+					}
+					Key::V if event.modifiers.opt() => {
+						tracing::trace!("M-v, splitting vertically");
+						self.split_focused(ContainerKind::Vertical).await;
+						return;
+					}
+					Key::A if event.modifiers.opt() && event.modifiers.shift(false) => {
+						tracing::trace!("M-S-a, focusing child");
+						self.focus_child().await;
+						return;
+					}
+					Key::A if event.modifiers.opt() => {
+						tracing::trace!("M-a, focusing parent");
+						self.focus_parent().await;
+						return;
+					}
 					_ => {}
 				}
 			}
 		}
 
-		let surface_id = match &event {
+		if self.state.fullscreen_surface.is_none() && self.handle_border_drag(&event).await {
+			return;
+		}
+
+		let surface_id = match &mut event {
 			rmox_input::Event::Key(_) | rmox_input::Event::Text(_) | rmox_input::Event::Button(_) => {
 				let Some(surface_id) = self.focused_surface() else {
 					return;
 				};
 				surface_id
 			}
-			rmox_input::Event::Touch(_) | rmox_input::Event::Stylus(_) => {
-				// TODO: Find the surface based on the location of the event.
-				// Also, we will need to send a leave event to one surface and an enter event to another in some cases.
-				tracing::warn!(?event, "touch/stylus event not yet implemented");
-				return;
+			rmox_input::Event::Touch(event) => {
+				let Some(surface_id) = self.touch_target(event) else {
+					return;
+				};
+				surface_id
+			}
+			rmox_input::Event::Stylus(event) => {
+				let Some(surface_id) = self.stylus_target(event).await else {
+					return;
+				};
+				surface_id
 			}
 			rmox_input::Event::DevicePresence(_) => return,
 		};
+		// Sending `Leave` in `stylus_target` may have removed the task owning this surface.
+		let Some(surface) = self.state.surfaces.get(&surface_id) else {
+			return;
+		};
+		let screen_size = self.state.config.screen_size;
+		let touch_state = |id| {
+			TouchState::new(
+				self.input.touch_state(id).unwrap(),
+				screen_size,
+				&surface.description,
+			)
+		};
+		let stylus_state = self
+			.input
+			.stylus_state()
+			.map(|state| StylusState::new(state, screen_size, &surface.description));
 		let event = match event {
 			rmox_input::Event::Key(v) => InputEvent::Key(v),
 			rmox_input::Event::Text(v) => InputEvent::Text(v),
@@ -672,48 +2542,181 @@ impl Manager {
 			rmox_input::Event::Touch(event) => InputEvent::Touch(TouchEvent {
 				id: event.touch_id,
 				phase: match event.phase {
-					rmox_input::touch::Phase::Start => {
-						TouchPhase::Start(self.input.touch_state(event.touch_id).unwrap())
-					}
-					rmox_input::touch::Phase::Change => {
-						TouchPhase::Change(self.input.touch_state(event.touch_id).unwrap())
-					}
+					rmox_input::touch::Phase::Start => TouchPhase::Start(touch_state(event.touch_id)),
+					rmox_input::touch::Phase::Change => TouchPhase::Change(touch_state(event.touch_id)),
 					rmox_input::touch::Phase::End => TouchPhase::End,
 				},
 			}),
 			rmox_input::Event::Stylus(event) => InputEvent::Stylus(StylusEvent {
 				phase: match event.phase {
-					rmox_input::stylus::Phase::Hover => {
-						StylusPhase::Hover(self.input.stylus_state().unwrap())
-					}
-					rmox_input::stylus::Phase::Touch => {
-						StylusPhase::Touch(self.input.stylus_state().unwrap())
-					}
-					rmox_input::stylus::Phase::Change => {
-						StylusPhase::Change(self.input.stylus_state().unwrap())
-					}
-					rmox_input::stylus::Phase::Lift => StylusPhase::Lift(self.input.stylus_state().unwrap()),
+					rmox_input::stylus::Phase::Hover => StylusPhase::Hover(stylus_state.unwrap()),
+					rmox_input::stylus::Phase::Touch => StylusPhase::Touch(stylus_state.unwrap()),
+					rmox_input::stylus::Phase::Change => StylusPhase::Change(stylus_state.unwrap()),
+					rmox_input::stylus::Phase::Lift => match stylus_state {
+						Some(state) => StylusPhase::Lift(state),
+						// The stylus was lifted and moved out of range at once, so leaving implies lifting.
+						None => StylusPhase::Leave,
+					},
 					rmox_input::stylus::Phase::Leave => StylusPhase::Leave,
 				},
 			}),
 			rmox_input::Event::DevicePresence(_) => return,
 		};
-		let surface = self.state.surfaces.get(&surface_id).unwrap();
-		let task_id = surface.task;
-		let task = self.state.tasks.get(&task_id).unwrap();
-		let event = Event::Surface {
-			id: surface_id,
-			event: SurfaceEvent::Input(event),
-		};
-		if task.channel.send(event).await.is_err() {
-			self.remove_task(task_id).await;
-		}
+		self
+			.send_surface_event(surface_id, SurfaceEvent::Input(event))
+			.await;
 	}
 }
 
 impl ManagerHandle {
-	async fn create_surface(&self, task: TaskId, options: SurfaceInit) {
-		let command = ManagerCommand::CreateSurface { task, options };
+	async fn create_surface(&self, task: TaskId, serial: Serial, options: SurfaceInit) {
+		let command = ManagerCommand::CreateSurface {
+			task,
+			serial,
+			options,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn destroy_surface(&self, task: TaskId, serial: Serial, surface: SurfaceId) {
+		let command = ManagerCommand::DestroySurface {
+			task,
+			serial,
+			surface,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn list_surfaces(&self, task: TaskId, serial: Serial) {
+		let command = ManagerCommand::ListSurfaces { task, serial };
+		self.channel.send(command).await.unwrap();
+	}
+
+	#[allow(clippy::too_many_arguments)] // Mirrors `Command::Commit`.
+	async fn commit(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		generation: u32,
+		damage: Vec<Rectangle>,
+		style: UpdateStyle,
+		depth: UpdateDepth,
+	) {
+		let command = ManagerCommand::Commit {
+			task,
+			serial,
+			surface,
+			generation,
+			damage,
+			style,
+			depth,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn set_title(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		title: Option<Box<str>>,
+	) {
+		let command = ManagerCommand::SetTitle {
+			task,
+			serial,
+			surface,
+			title,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn set_app_id(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		app_id: Option<Box<str>>,
+	) {
+		let command = ManagerCommand::SetAppId {
+			task,
+			serial,
+			surface,
+			app_id,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn offer_selection(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_types: Vec<Box<str>>,
+	) {
+		let command = ManagerCommand::OfferSelection {
+			task,
+			serial,
+			selection,
+			mime_types,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn request_selection(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		selection: Selection,
+		mime_type: Box<str>,
+	) {
+		let command = ManagerCommand::RequestSelection {
+			task,
+			serial,
+			selection,
+			mime_type,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn send_selection(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		transfer: TransferId,
+		data: Option<Vec<u8>>,
+	) {
+		let command = ManagerCommand::SendSelection {
+			task,
+			serial,
+			transfer,
+			data,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn screenshot(&self, task: TaskId, serial: Serial, surface: Option<SurfaceId>) {
+		let command = ManagerCommand::Screenshot {
+			task,
+			serial,
+			surface,
+		};
+		self.channel.send(command).await.unwrap();
+	}
+
+	async fn request_fullscreen(
+		&self,
+		task: TaskId,
+		serial: Serial,
+		surface: SurfaceId,
+		fullscreen: bool,
+	) {
+		let command = ManagerCommand::RequestFullscreen {
+			task,
+			serial,
+			surface,
+			fullscreen,
+		};
 		self.channel.send(command).await.unwrap();
 	}
 
@@ -744,14 +2747,23 @@ async fn main() {
 	let control_socket = tokio::net::UnixListener::bind(&args.control_socket)
 		.unwrap_or_else(|error| panic!("opening socket at {:?}: {error}", args.control_socket));
 
-	let mut fb = Framebuffer::open().expect("open framebuffer");
+	let fb_config = FramebufferConfig::from_env().expect("read framebuffer config");
+	let mut fb = Framebuffer::open_with(&fb_config).expect("open framebuffer");
 
 	fb.clear(Rgb565::new(31, 63, 31)).unwrap();
-	fb.update_all(UpdateStyle::Init).unwrap();
-	std::thread::sleep(Duration::from_millis(500));
+	let res = fb
+		.update_all_async(UpdateStyle::Init)
+		.await
+		.unwrap()
+		.wait()
+		.await;
+	if let Err(error) = res {
+		tracing::warn!(?error, "waiting for initial clear");
+	}
 	tracing::info!("cleared");
 
 	let config = ManagerConfig {
+		screen_size: fb.rect().size,
 		global_rotation: Rotation::Rotate90,
 		inset: 4,
 		control_socket: args.control_socket.into(),
@@ -779,8 +2791,38 @@ async fn main() {
 			}
 			Some(command) = command_recv.recv() => {
 				match command {
-					ManagerCommand::CreateSurface { task, options } => {
-						manager.create_surface(task, options).await;
+					ManagerCommand::CreateSurface { task, serial, options } => {
+						manager.create_surface(task, serial, options).await;
+					}
+					ManagerCommand::DestroySurface { task, serial, surface } => {
+						manager.destroy_surface(task, serial, surface).await;
+					}
+					ManagerCommand::ListSurfaces { task, serial } => {
+						manager.list_surfaces(task, serial).await;
+					}
+					ManagerCommand::Commit { task, serial, surface, generation, damage, style, depth } => {
+						manager.commit(task, serial, surface, generation, &damage, style, depth, &mut fb).await;
+					}
+					ManagerCommand::SetTitle { task, serial, surface, title } => {
+						manager.set_surface_info(task, serial, surface, |info| info.title = title).await;
+					}
+					ManagerCommand::SetAppId { task, serial, surface, app_id } => {
+						manager.set_surface_info(task, serial, surface, |info| info.app_id = app_id).await;
+					}
+					ManagerCommand::OfferSelection { task, serial, selection, mime_types } => {
+						manager.offer_selection(task, serial, selection, mime_types).await;
+					}
+					ManagerCommand::RequestSelection { task, serial, selection, mime_type } => {
+						manager.request_selection(task, serial, selection, mime_type).await;
+					}
+					ManagerCommand::SendSelection { task, serial, transfer, data } => {
+						manager.send_selection(task, serial, transfer, data).await;
+					}
+					ManagerCommand::Screenshot { task, serial, surface } => {
+						manager.screenshot(task, serial, surface, &fb).await;
+					}
+					ManagerCommand::RequestFullscreen { task, serial, surface, fullscreen } => {
+						manager.request_fullscreen(task, serial, surface, fullscreen).await;
 					}
 					ManagerCommand::RemoveTask { task } => {
 						manager.remove_task(task).await;
@@ -799,5 +2841,589 @@ async fn main() {
 				manager.handle_input(event).await;
 			}
 		}
+
+		manager.draw_tab_strips(&mut fb).await;
+		manager.clear_margins(&mut fb).await;
+	}
+}
+
+/// Builds a container of `kind` with `children`, for tests.
+#[cfg(test)]
+fn test_container(kind: ContainerKind, children: impl IntoIterator<Item = ShellNode>) -> Container {
+	let mut children = children.into_iter();
+	let mut container = Container::new(kind, children.next().unwrap());
+	for child in children {
+		container.insert(container.children.len(), child);
+	}
+	container
+}
+
+/// The `n`th surface ID, for tests.
+#[cfg(test)]
+fn test_surface(n: u32) -> ShellNode {
+	ShellNode::Surface(SurfaceId((1..n).fold(Id::START, |id, _| id.step())))
+}
+
+/// The ID of `test_surface(n)`, for tests.
+#[cfg(test)]
+fn test_surface_id(n: u32) -> SurfaceId {
+	let ShellNode::Surface(id) = test_surface(n) else {
+		unreachable!();
+	};
+	id
+}
+
+/// A state for a 100x200 screen with `test_surface(1)` to `test_surface(n)`, for tests.
+#[cfg(test)]
+fn test_state(n: u32) -> ManagerState {
+	let mut state = ManagerState::new(ManagerConfig {
+		screen_size: rmox_common::types::vec2(100, 200),
+		global_rotation: Rotation::None,
+		inset: 0,
+		control_socket: OsString::new(),
+	});
+	for n in 1..=n {
+		let surface = Surface::new(TaskId(Id::START), Rotation::None);
+		state.surfaces.insert(test_surface_id(n), surface);
+	}
+	state
+}
+
+/// A compact description of the tree, such as `H[1 V[2 3]]`, for tests.
+#[cfg(test)]
+fn describe_container(container: &Container) -> String {
+	let kind = match container.kind {
+		ContainerKind::Horizontal => "H",
+		ContainerKind::Vertical => "V",
+		ContainerKind::Tabbed => "T",
+		ContainerKind::Stacked => "S",
+	};
+	let children: Vec<String> = container
+		.children
+		.iter()
+		.map(|child| match &child.node {
+			ShellNode::Container(container) => describe_container(container),
+			ShellNode::Surface(id) => (1..)
+				.find(|&n| matches!(test_surface(n), ShellNode::Surface(other) if other == *id))
+				.unwrap()
+				.to_string(),
+		})
+		.collect();
+	format!("{kind}[{}]", children.join(" "))
+}
+
+#[test]
+fn test_move_into_neighbor() {
+	use ContainerKind::{Horizontal as H, Vertical as V};
+
+	let mut root = test_container(
+		H,
+		[
+			test_surface(1),
+			ShellNode::Container(test_container(V, [test_surface(2), test_surface(3)])),
+		],
+	);
+	// The surface is moved to the start of the container, which is left as the only child.
+	assert_eq!(root.move_toward(&[0], Side::Right), Some(vec![0, 0]));
+	assert_eq!(describe_container(&root), "H[V[1 2 3]]");
+
+	// Within a container along the direction, surfaces are swapped.
+	assert_eq!(root.move_toward(&[0, 0], Side::Bottom), Some(vec![0, 1]));
+	assert_eq!(describe_container(&root), "H[V[2 1 3]]");
+}
+
+#[test]
+fn test_move_out_to_ancestor() {
+	use ContainerKind::{Horizontal as H, Vertical as V};
+
+	let mut root = test_container(
+		H,
+		[
+			test_surface(1),
+			ShellNode::Container(test_container(V, [test_surface(2), test_surface(3)])),
+		],
+	);
+	// The parent is not along the direction, so the surface is placed next to it in the root.
+	assert_eq!(root.move_toward(&[1, 0], Side::Left), Some(vec![1]));
+	assert_eq!(describe_container(&root), "H[1 2 V[3]]");
+	// The container that is left empty is removed.
+	assert_eq!(root.move_toward(&[2, 0], Side::Right), Some(vec![2]));
+	assert_eq!(describe_container(&root), "H[1 2 3]");
+	// Already at the edge of the screen.
+	assert_eq!(root.move_toward(&[2], Side::Right), None);
+}
+
+#[test]
+fn test_move_nests_root() {
+	use ContainerKind::Horizontal as H;
+
+	let mut root = test_container(H, [test_surface(1), test_surface(2)]);
+	// Nothing is along the direction, so the root is nested in a new vertical container.
+	assert_eq!(root.move_toward(&[1], Side::Top), Some(vec![0]));
+	assert_eq!(describe_container(&root), "V[2 H[1]]");
+	assert_eq!(root.move_toward(&[0], Side::Bottom), Some(vec![0, 0]));
+	assert_eq!(describe_container(&root), "V[H[2 1]]");
+}
+
+#[test]
+fn test_move_node_prunes_placeholder() {
+	use ContainerKind::{Horizontal as H, Vertical as V};
+
+	let mut root = test_container(
+		H,
+		[
+			test_surface(1),
+			ShellNode::Container(test_container(
+				V,
+				[ShellNode::Container(test_container(H, [test_surface(2)]))],
+			)),
+		],
+	);
+	// Moving a container moves all of its surfaces, and the containers left empty are removed.
+	assert_eq!(root.move_node(&[1, 0], &[], 0), Some(vec![0]));
+	assert_eq!(describe_container(&root), "H[H[2] 1]");
+	// The root cannot be moved.
+	assert_eq!(root.move_toward(&[], Side::Left), None);
+}
+
+#[test]
+fn test_child_rects_tile_container() {
+	use ContainerKind::Horizontal as H;
+
+	let mut container = test_container(H, [test_surface(1), test_surface(2), test_surface(3)]);
+	container.rect = rect(10, 20, 100, 50);
+	// The ends are rounded down, and the last child absorbs the remainder so that the children exactly cover the container.
+	assert_eq!(
+		container.child_rects(Side::Left),
+		[
+			rect(10, 20, 33, 50),
+			rect(43, 20, 33, 50),
+			rect(76, 20, 34, 50)
+		],
+	);
+	assert_eq!(
+		container.child_rects(Side::Right),
+		[
+			rect(77, 20, 33, 50),
+			rect(44, 20, 33, 50),
+			rect(10, 20, 34, 50)
+		],
+	);
+
+	container.children[1].weight = 2 * DEFAULT_WEIGHT;
+	assert_eq!(
+		container.child_rects(Side::Left),
+		[
+			rect(10, 20, 25, 50),
+			rect(35, 20, 50, 50),
+			rect(85, 20, 25, 50)
+		],
+	);
+}
+
+#[test]
+fn test_scale_weight_keeps_min_size() {
+	use ContainerKind::Vertical as V;
+
+	let mut container = test_container(V, [test_surface(1), test_surface(2)]);
+	container.rect = rect(0, 0, 100, 200);
+	assert!(container.scale_weight(0, 2, 1, Side::Top));
+	assert_eq!(
+		container.child_rects(Side::Top),
+		[rect(0, 0, 100, 133), rect(0, 133, 100, 67)],
+	);
+	// Growing the first child again would leave the second smaller than `MIN_CHILD_SIZE`.
+	assert!(!container.scale_weight(0, 2, 1, Side::Top));
+	// As would shrinking the first child to almost nothing.
+	assert!(!container.scale_weight(0, 1, 1000, Side::Top));
+	assert_eq!(container.children[0].weight, 2 * DEFAULT_WEIGHT);
+	assert_eq!(container.children[1].weight, DEFAULT_WEIGHT);
+}
+
+#[test]
+fn test_drag_border_clamps_to_min_size() {
+	use ContainerKind::Horizontal as H;
+
+	let lengths = |container: &Container| -> Vec<i32> {
+		container
+			.child_rects(Side::Left)
+			.iter()
+			.map(|rect| rect.size.x)
+			.collect()
+	};
+
+	let mut container = test_container(H, [test_surface(1), test_surface(2), test_surface(3)]);
+	container.rect = rect(0, 0, 300, 100);
+	container.drag_border(0, Pos2 { x: 120, y: 50 }, Side::Left);
+	assert_eq!(lengths(&container), [120, 80, 100]);
+	// The second child keeps `MIN_CHILD_SIZE`, and the third child is unaffected.
+	container.drag_border(0, Pos2 { x: 190, y: 50 }, Side::Left);
+	assert_eq!(lengths(&container), [136, 64, 100]);
+	container.drag_border(1, Pos2 { x: 299, y: 50 }, Side::Left);
+	assert_eq!(lengths(&container), [136, 100, 64]);
+	container.drag_border(0, Pos2 { x: 0, y: 50 }, Side::Left);
+	assert_eq!(lengths(&container), [64, 172, 64]);
+}
+
+#[test]
+fn test_border_at() {
+	use ContainerKind::{Horizontal as H, Vertical as V};
+
+	let mut nested = test_container(V, [test_surface(2), test_surface(3)]);
+	nested.rect = rect(100, 0, 100, 100);
+	let mut root = test_container(H, [test_surface(1), ShellNode::Container(nested)]);
+	root.rect = rect(0, 0, 200, 100);
+
+	let border_at = |x, y| {
+		let mut path = Vec::new();
+		let index = root.border_at(Pos2 { x, y }, Rotation::None, &mut path)?;
+		Some((path, index))
+	};
+	// Up to `BORDER_GRAB_DISTANCE` on either side of the border between the root's children.
+	assert_eq!(border_at(96, 10), Some((vec![], 0)));
+	assert_eq!(border_at(104, 10), Some((vec![], 0)));
+	assert_eq!(border_at(95, 10), None);
+	assert_eq!(border_at(105, 10), None);
+	// The border in the nested container is preferred where they overlap.
+	assert_eq!(border_at(150, 50), Some((vec![1], 0)));
+	assert_eq!(border_at(102, 48), Some((vec![1], 0)));
+	// The edges of the root container are not borders.
+	assert_eq!(border_at(0, 10), None);
+	assert_eq!(border_at(199, 10), None);
+	assert_eq!(border_at(200, 10), None);
+}
+
+#[test]
+fn test_input_near_border_goes_to_surface() {
+	use ContainerKind::Horizontal as H;
+
+	let mut state = test_state(2);
+	let mut shell = Shell {
+		layers: Vec::new(),
+		root: Some(test_container(H, [test_surface(1), test_surface(2)])),
+		wallpaper: None,
+	};
+	state.assign_areas(&mut shell, &mut Vec::new());
+
+	// The border between the surfaces is at x = 50.
+	let target = |x| {
+		let point = Pos2 { x, y: 100 };
+		if shell.border_at(point, Rotation::None).is_some() {
+			return None;
+		}
+		shell.surface_at(point, |id| state.surfaces[&id].description.base_rect)
+	};
+	assert_eq!(target(50), None);
+	assert_eq!(target(50 - BORDER_GRAB_DISTANCE), None);
+	assert_eq!(target(50 + BORDER_GRAB_DISTANCE), None);
+	assert_eq!(
+		target(50 - BORDER_GRAB_DISTANCE - 1),
+		Some(test_surface_id(1))
+	);
+	assert_eq!(
+		target(50 + BORDER_GRAB_DISTANCE + 1),
+		Some(test_surface_id(2))
+	);
+	assert_eq!(target(40), Some(test_surface_id(1)));
+	assert_eq!(target(60), Some(test_surface_id(2)));
+}
+
+#[test]
+fn test_assign_areas_resizes_buffers() {
+	use ContainerKind::Horizontal as H;
+
+	let mut state = test_state(3);
+	let mut shell = Shell {
+		layers: vec![ShellLayer {
+			anchor: Side::Top,
+			size: 20,
+			surface: test_surface_id(3),
+		}],
+		root: Some(test_container(H, [test_surface(1), test_surface(2)])),
+		wallpaper: None,
+	};
+	let mut dirty_surfaces = Vec::new();
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	assert_eq!(dirty_surfaces.len(), 3);
+	for surface in state.surfaces.values() {
+		let buffers = surface.buffers.as_ref().unwrap();
+		assert_eq!(buffers.size(), surface.description.base_rect.size);
+		assert!(surface.unsent_buffers.is_some());
+	}
+
+	// A pass interrupted after sending the layer's description, by removing the task of surface 1.
+	dirty_surfaces.retain(|&id| id != test_surface_id(3));
+	state.surfaces.remove(&test_surface_id(1));
+	shell.retain(|id| state.surfaces.contains_key(&id));
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	dirty_surfaces.retain(|id| state.surfaces.contains_key(id));
+	// Surface 2 is still dirty, and its buffers were replaced to match its new area.
+	assert_eq!(dirty_surfaces, [test_surface_id(2)]);
+	let surface = &state.surfaces[&test_surface_id(2)];
+	assert_eq!(surface.description.base_rect, rect(0, 20, 100, 180));
+	assert_eq!(
+		surface.buffers.as_ref().unwrap().size(),
+		surface.description.base_rect.size
+	);
+	assert_eq!(surface.buffers_generation, 2);
+}
+
+#[test]
+fn test_prune_keeps_focused_container() {
+	use ContainerKind::{Horizontal as H, Vertical as V};
+
+	let mut shell = Shell {
+		layers: Vec::new(),
+		root: Some(test_container(
+			H,
+			[
+				test_surface(1),
+				ShellNode::Container(test_container(V, [test_surface(2), test_surface(3)])),
+				test_surface(4),
+			],
+		)),
+		wallpaper: None,
+	};
+	let mut focused = Some(vec![1]);
+	// The focused container moves when a surface before it is removed.
+	shell.retain_focused(|id| id != test_surface_id(1), &mut focused);
+	assert_eq!(focused, Some(vec![0]));
+	// The container stays focused rather than one of its surfaces.
+	shell.retain_focused(|id| id != test_surface_id(2), &mut focused);
+	assert_eq!(focused, Some(vec![0]));
+	// Once the container itself is removed, a surface is focused instead.
+	shell.retain_focused(|id| id != test_surface_id(3), &mut focused);
+	assert_eq!(describe_container(shell.root.as_ref().unwrap()), "H[4]");
+	assert_eq!(focused, Some(vec![0]));
+
+	let mut focused = Some(vec![]);
+	// The root container stays focused.
+	shell.retain_focused(|_| true, &mut focused);
+	assert_eq!(focused, Some(vec![]));
+}
+
+#[test]
+fn test_fullscreen() {
+	use ContainerKind::Horizontal as H;
+
+	let mut state = test_state(4);
+	let mut shell = Shell {
+		layers: vec![ShellLayer {
+			anchor: Side::Top,
+			size: 20,
+			surface: test_surface_id(4),
+		}],
+		root: Some(test_container(
+			H,
+			[test_surface(1), test_surface(2), test_surface(3)],
+		)),
+		wallpaper: None,
+	};
+	let mut dirty_surfaces = Vec::new();
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	let layout = |state: &ManagerState| -> Vec<(Rectangle, bool)> {
+		(1..=4)
+			.map(|n| {
+				let description = state.surfaces[&test_surface_id(n)].description;
+				(description.base_rect, description.visible)
+			})
+			.collect()
+	};
+	let tiled = [
+		(rect(0, 20, 33, 180), true),
+		(rect(33, 20, 33, 180), true),
+		(rect(66, 20, 34, 180), true),
+		(rect(0, 0, 100, 20), true),
+	];
+	assert_eq!(layout(&state), tiled);
+
+	// Only surfaces in the tree can be made fullscreen.
+	assert_eq!(
+		state.set_fullscreen(&shell, test_surface_id(4), true),
+		Err(())
+	);
+
+	state
+		.set_fullscreen(&shell, test_surface_id(2), true)
+		.unwrap();
+	assert_eq!(state.keyboard_focused_container, Some(vec![1]));
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	// The fullscreen surface covers the whole screen, and the others are hidden without being resized.
+	assert_eq!(
+		layout(&state),
+		[
+			(rect(0, 20, 33, 180), false),
+			(rect(0, 0, 100, 200), true),
+			(rect(66, 20, 34, 180), false),
+			(rect(0, 0, 100, 20), false),
+		],
+	);
+
+	state
+		.set_fullscreen(&shell, test_surface_id(2), false)
+		.unwrap();
+	assert_eq!(state.fullscreen_surface, None);
+	assert!(state.margins_dirty);
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	assert_eq!(layout(&state), tiled);
+
+	// Removing the fullscreen surface returns the others to the layout.
+	state.margins_dirty = false;
+	state
+		.set_fullscreen(&shell, test_surface_id(2), true)
+		.unwrap();
+	state.surfaces.remove(&test_surface_id(2));
+	state.prune_shell(&mut shell);
+	assert_eq!(state.fullscreen_surface, None);
+	assert!(state.margins_dirty);
+	state.assign_areas(&mut shell, &mut dirty_surfaces);
+	let description = |n| state.surfaces[&test_surface_id(n)].description;
+	assert_eq!(description(1).base_rect, rect(0, 20, 50, 180));
+	assert!(description(1).visible);
+	assert_eq!(description(3).base_rect, rect(50, 20, 50, 180));
+	assert!(description(3).visible);
+	assert!(description(4).visible);
+}
+
+#[test]
+fn test_tabbed_and_stacked_layout() {
+	use ContainerKind::{Horizontal as H, Stacked as S, Tabbed as T, Vertical as V};
+
+	let mut state = test_state(4);
+	let mut root = test_container(
+		V,
+		[
+			ShellNode::Container(test_container(T, [test_surface(1), test_surface(2)])),
+			ShellNode::Container(test_container(S, [test_surface(3), test_surface(4)])),
+		],
+	);
+	root.record_focus(&[0, 1]);
+	let mut shell = Shell {
+		layers: Vec::new(),
+		root: Some(root),
+		wallpaper: None,
+	};
+	state.assign_areas(&mut shell, &mut Vec::new());
+	let layout: Vec<_> = (1..=4)
+		.map(|n| {
+			let description = state.surfaces[&test_surface_id(n)].description;
+			(description.base_rect, description.visible)
+		})
+		.collect();
+	// Hidden children keep the area of the visible one, below the tab strip.
+	assert_eq!(
+		layout,
+		[
+			(rect(0, 32, 100, 68), false),
+			(rect(0, 32, 100, 68), true),
+			(rect(0, 164, 100, 36), true),
+			(rect(0, 164, 100, 36), false),
+		],
+	);
+
+	// Tabbed containers have a single row of tabs, and stacked containers have a row for each child.
+	let title = |id: SurfaceId| -> Box<str> {
+		(1..)
+			.find(|&n| test_surface_id(n) == id)
+			.unwrap()
+			.to_string()
+			.into()
+	};
+	let mut strips = Vec::new();
+	shell
+		.root
+		.as_ref()
+		.unwrap()
+		.tab_strips(Rotation::None, &title, &mut strips);
+	assert_eq!(
+		strips,
+		[
+			TabStrip {
+				rect: rect(0, 0, 100, 32),
+				stacked: false,
+				titles: vec!["1".into(), "2".into()],
+				focused: 1,
+			},
+			TabStrip {
+				rect: rect(0, 100, 100, 64),
+				stacked: true,
+				titles: vec!["3".into(), "4".into()],
+				focused: 0,
+			},
+		],
+	);
+
+	// Input goes to the visible children only, and not to the tab strips.
+	let surface_at = |x, y| {
+		shell.surface_at(Pos2 { x, y }, |id| {
+			state.surfaces[&id].description.base_rect
+		})
+	};
+	assert_eq!(surface_at(50, 50), Some(test_surface_id(2)));
+	assert_eq!(surface_at(50, 180), Some(test_surface_id(3)));
+	assert_eq!(surface_at(50, 10), None);
+	assert_eq!(surface_at(50, 150), None);
+
+	// Only the border between the tabbed and stacked containers can be dragged.
+	let border_at = |x, y| shell.border_at(Pos2 { x, y }, Rotation::None);
+	assert_eq!(border_at(50, 100).unwrap().container, []);
+	assert!(border_at(50, 50).is_none());
+	assert!(border_at(50, 180).is_none());
+	// Not even where the borders between the children would be if the container were split.
+	let mut tabbed = test_container(T, [test_surface(1), test_surface(2), test_surface(3)]);
+	tabbed.rect = rect(0, 0, 300, 100);
+	for x in [100, 200] {
+		assert_eq!(
+			tabbed.border_at(Pos2 { x, y: 50 }, Rotation::None, &mut Vec::new()),
+			None,
+		);
 	}
+	// A split container inside a tabbed or stacked one still has its borders.
+	let mut split = test_container(H, [test_surface(1), test_surface(2)]);
+	split.rect = rect(0, 32, 100, 68);
+	let mut stacked = test_container(S, [ShellNode::Container(split)]);
+	stacked.rect = rect(0, 0, 100, 100);
+	let mut path = Vec::new();
+	assert_eq!(
+		stacked.border_at(Pos2 { x: 50, y: 60 }, Rotation::None, &mut path),
+		Some(0),
+	);
+	assert_eq!(path, [0]);
+}
+
+#[test]
+fn test_cycle_tab() {
+	use ContainerKind::{Horizontal as H, Tabbed as T};
+
+	let mut root = test_container(
+		H,
+		[
+			test_surface(1),
+			ShellNode::Container(test_container(
+				T,
+				[
+					ShellNode::Container(test_container(H, [test_surface(2), test_surface(3)])),
+					test_surface(4),
+					test_surface(5),
+				],
+			)),
+		],
+	);
+	// Wraps around in both directions.
+	assert_eq!(root.cycle_tab(&[1, 1], true), Some(vec![1, 2]));
+	assert_eq!(root.cycle_tab(&[1, 2], true), Some(vec![1, 0]));
+	assert_eq!(root.cycle_tab(&[1, 0], false), Some(vec![1, 2]));
+	// From deeper inside a tab, the nearest tabbed container around it is cycled.
+	assert_eq!(root.cycle_tab(&[1, 0, 1], true), Some(vec![1, 1]));
+	// Outside of any tabbed or stacked container.
+	assert_eq!(root.cycle_tab(&[0], true), None);
+	assert_eq!(root.cycle_tab(&[], true), None);
+
+	// Returning to a tab leads back to the surface that was last focused within it.
+	root.record_focus(&[1, 0, 1]);
+	let mut path = root.cycle_tab(&[1, 0, 1], true).unwrap();
+	root.record_focus(&path);
+	path = root.cycle_tab(&path, false).unwrap();
+	root.fix_path(&mut path, 0);
+	assert_eq!(path, [1, 0, 1]);
 }